@@ -1,36 +1,117 @@
 use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
     fs,
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{self, AtomicBool},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{indexchanges::IndexChange, repr_file::ReprFile};
+use clap::Args;
+
+use crate::{
+    args::{parse_duration, parse_size},
+    delta::{self, BlockHashes},
+    identity,
+    index::{Index, RESERVED_PREFIX},
+    indexchanges::IndexChange,
+    indexfile::{IndexFile, SpecialFile},
+    resume,
+    source::Source,
+};
+
+/// Options for applying changes
+#[derive(Clone, Args)]
+pub struct ApplySettings {
+    /// how many changes are applied at the same time.
+    /// this helps with many small files on targets with a high latency, like network filesystems.
+    #[arg(long, default_value_t = 1)]
+    pub apply_threads: usize,
+    /// copies of files which are at least this large can be resumed if they are interrupted,
+    /// instead of starting over during the next backup.
+    #[arg(long, value_parser = parse_size, default_value = "256MiB")]
+    pub resume_min_size: u64,
+    /// the blocks of files which are at least this large are hashed while copying them,
+    /// so when they change, only the blocks which changed have to be written to the target.
+    #[arg(long, value_parser = parse_size, default_value = "64MiB")]
+    pub delta_min_size: u64,
+    /// stop before copying more than this, like `50GiB`.
+    /// the remaining changes will be applied during the next backup.
+    /// a file which is larger than this is still copied if it's the first one.
+    #[arg(long, value_parser = parse_size)]
+    pub max_transfer: Option<u64>,
+    /// stop applying changes after this, like `2h`.
+    /// the remaining changes will be applied during the next backup.
+    #[arg(long, value_parser = parse_duration)]
+    pub max_duration: Option<Duration>,
+}
+
+/// How many changes couldn't be applied
+pub struct Applied {
+    /// changes which failed
+    pub failures: usize,
+    /// changes which weren't tried because --max-transfer or --max-duration was reached
+    pub remaining: usize,
+}
 
 /// Only errors that happen when writing to the index are immediately returned.
 /// Other errors are logged to stderr and the failed change will not be saved to the index,
 /// so the next backup will try again.
+/// The same goes for changes which aren't applied because --max-transfer or --max-duration was reached.
 pub fn apply_indexchanges(
-    source: &Path,
-    index: &Path,
+    source: &Source,
+    index: &mut Index,
     target: &Option<PathBuf>,
     changes: &[IndexChange],
     gib_total: Option<f64>,
-) -> usize {
-    // do symlinks last, as they cd, which can fail,
-    // and if it does, it would be fatal and stop the backup.
-    let (mut changes, symlink_additions) =
-        changes.into_iter().partition::<Vec<_>, _>(|c| match c {
-            IndexChange::AddDir(..)
-            | IndexChange::AddFile(..)
-            | IndexChange::RemoveFile(..)
-            | IndexChange::RemoveDir(..) => true,
-            IndexChange::AddSymlink(..) => false,
-        });
-    changes.extend(symlink_additions);
-
+    apply_settings: &ApplySettings,
+) -> Applied {
+    if let Some(target) = target
+        && let Err(e) = identity::mark_target(index, target)
+    {
+        eprintln!(
+            "[err] couldn't mark {target:?} as the target of {:?}: {e}\n      No changes were applied.",
+            index.path()
+        );
+        return Applied {
+            failures: changes.len(),
+            remaining: 0,
+        };
+    }
     let mut failures = changes.len();
-    apply_indexchanges_int(source, index, target, &changes, gib_total, &mut failures);
+    let remaining = apply_indexchanges_int(
+        source,
+        index,
+        target,
+        changes,
+        gib_total,
+        apply_settings,
+        &mut failures,
+    );
+    failures -= remaining;
     eprintln!();
-    failures
+    if let Err(e) = index.save() {
+        eprintln!(
+            "[err] couldn't save index {:?}: {e}\n      All changes will be applied again during the next backup.",
+            index.path()
+        );
+        failures = changes.len();
+    }
+    Applied {
+        failures,
+        remaining,
+    }
 }
 
 fn eprint_constants(changes_total: usize, gib_total: f64) -> (usize, usize, usize) {
@@ -79,29 +160,66 @@ fn eprint_status(
     );
 }
 
+/// What happened when a change was applied to the target
+#[derive(Clone, Copy)]
+enum Outcome {
+    Failed,
+    Done,
+    /// the file was created, but its permissions, owner or modification time couldn't be set
+    MetadataFailed,
+}
+
+/// Applies the changes using `apply_settings.apply_threads` worker threads.
+/// The target is changed by the workers, the index only by this thread, after a change was applied to the target.
+/// A change is only started once all earlier changes to the same path, its parent directories,
+/// or anything in it are done, so the order of the `changes` is kept where it matters.
+/// Returns how many changes weren't started because --max-transfer or --max-duration was reached.
 pub fn apply_indexchanges_int(
-    source: &Path,
-    index: &Path,
+    source: &Source,
+    index: &mut Index,
     target: &Option<PathBuf>,
-    changes: &[&IndexChange],
+    changes: &[IndexChange],
     gib_total: Option<f64>,
+    apply_settings: &ApplySettings,
     failures: &mut usize,
-) {
+) -> usize {
+    let start = Instant::now();
     let changes_total = changes.len();
     let gib_total = gib_total.unwrap_or_else(|| {
         changes
             .iter()
-            .filter_map(|c| {
-                if let IndexChange::AddFile(_, i) = c {
-                    Some(i.size as f64 / (1024 * 1024 * 1024) as f64)
-                } else {
-                    None
-                }
-            })
+            .map(|c| c.transfer_size() as f64 / (1024 * 1024 * 1024) as f64)
             .sum()
     });
     let (prog_width, changes_len_width, gib_len_width) = eprint_constants(changes_total, gib_total);
+    let pending_path = index.pending_path();
+    let resumable = changes
+        .iter()
+        .filter_map(|change| match change {
+            IndexChange::AddFile(file, index_file)
+            | IndexChange::UpdateFile(file, index_file, _, _)
+                if index_file.size >= apply_settings.resume_min_size =>
+            {
+                Some(file.as_path())
+            }
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    // files which an interrupted backup was updating in place, which have to be copied again
+    let mut damaged = HashSet::new();
+    let mut pending = target.as_ref().and_then(|target| {
+        Pending::open(&pending_path, target, &resumable, &mut damaged)
+            .inspect_err(|e| {
+                eprintln!(
+                    "[warn] couldn't open {pending_path:?}: {e}\n       If the backup is interrupted, temporary files may be left on the target."
+                )
+            })
+            .ok()
+    });
+    let mut changes_applied = 0;
     let mut gib_transferred = 0.0;
+    // includes files which are still being copied
+    let mut bytes_transferred = 0;
     eprint_status(
         0,
         changes_total,
@@ -111,168 +229,140 @@ pub fn apply_indexchanges_int(
         changes_len_width,
         gib_len_width,
     );
-    for (i, change) in changes.iter().enumerate() {
-        match change {
-            IndexChange::AddDir(dir, make_new, _) => {
-                if *make_new {
-                    let ok = if let Some(target) = target {
-                        let t = target.join(dir);
-                        if let Err(e) = fs::create_dir_all(&t) {
-                            eprintln!("\n[warn] couldn't create directory {t:?}: {e}");
-                            false
-                        } else {
-                            true
-                        }
-                    } else {
-                        true
+    let threads = apply_settings.apply_threads.max(1);
+    let index_path = index.path().to_owned();
+    let (job_sender, job_receiver) = mpsc::channel::<usize>();
+    let job_receiver = Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+    let dir_times = match target {
+        Some(_) => recorded_dir_times(index, changes),
+        None => HashMap::new(),
+    };
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (job_receiver, result_sender, index_path, damaged, dir_times) = (
+                &job_receiver,
+                result_sender.clone(),
+                &index_path,
+                &damaged,
+                &dir_times,
+            );
+            scope.spawn(move || {
+                loop {
+                    let Ok(i) = job_receiver.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let mut blocks = None;
+                    let outcome = match target {
+                        // a panic only fails this change, the main thread would wait for it forever
+                        Some(target) => panic::catch_unwind(AssertUnwindSafe(|| {
+                            apply_to_target(
+                                source,
+                                target,
+                                index_path,
+                                &changes[i],
+                                apply_settings,
+                                damaged,
+                                &mut blocks,
+                            )
+                        }))
+                        .unwrap_or_else(|_| {
+                            eprintln!(
+                                "\n[err] applying a change to {:?} panicked.",
+                                changes[i].paths()
+                            );
+                            Outcome::Failed
+                        }),
+                        None => Outcome::Done,
                     };
-                    if ok {
-                        *failures -= 1;
-                        let t = index.join(dir);
-                        if let Err(e) = fs::create_dir_all(&t) {
-                            eprintln!("\n[warn] couldn't create index directory {t:?}: {e}");
-                        }
+                    if let Some(target) = target {
+                        restore_dir_modified(target, &changes[i], dir_times);
                     }
-                } else {
-                    *failures -= 1;
-                }
-            }
-            IndexChange::AddFile(file, index_file) => {
-                gib_transferred += index_file.size as f64 / (1024 * 1024 * 1024) as f64;
-                let ok = if let Some(target) = target {
-                    let s = source.join(file);
-                    let t = target.join(file);
-                    if let Err(e) = fs::copy(&s, &t) {
-                        eprintln!("\n[warn] couldn't copy file from {s:?} to {t:?}: {e}");
-                        false
-                    } else {
-                        true
-                    }
-                } else {
-                    true
-                };
-                if ok {
-                    *failures -= 1;
-                    let t = index.join(file);
-                    if let Err(e) = fs::write(&t, index_file.save()) {
-                        eprintln!("\n[warn] couldn't save index file {t:?}: {e}");
+                    if result_sender.send((i, outcome, blocks)).is_err() {
+                        break;
                     }
                 }
-            }
-            IndexChange::AddSymlink(file, link_target) => {
-                let cwd = match std::env::current_dir() {
-                    Ok(cwd) => cwd,
-                    Err(e) => {
-                        eprintln!("\n[err] fatal: couldn't get cwd: {e}");
-                        return;
-                    }
-                };
-                let ok = if let Some(target) = target {
-                    let t = target.join(file);
-                    if let Some(p) = t.parent() {
-                        let t = t.file_name().expect("a file should always have a filename");
-                        if let Err(e) = std::env::set_current_dir(&p) {
-                            eprintln!("\n[warn] couldn't cd to {p:?}: {e}");
-                            false
-                        } else {
-                            let _ = std::fs::remove_file(t);
-                            if let Err(e) = std::os::unix::fs::symlink(&link_target, t) {
-                                eprintln!(
-                                    "\n[warn] couldn't set file {t:?} to be a symlink to {link_target:?}: {e}"
-                                );
-                                false
-                            } else {
-                                if let Err(e) = std::env::set_current_dir(&cwd) {
-                                    eprintln!("\n[err] fatal: couldn't cd back to {cwd:?}: {e}");
-                                    return;
-                                }
-                                true
-                            }
-                        }
-                    } else {
-                        eprintln!("\n[warn] symlink path was empty");
-                        false
-                    }
+            });
+        }
+        drop(result_sender);
+        let mut in_progress = Vec::<usize>::new();
+        let mut started = 0;
+        let mut stopped = false;
+        loop {
+            while !stopped
+                && started < changes_total
+                && in_progress.len() < threads
+                && !in_progress
+                    .iter()
+                    .any(|i| depends_on(&changes[started], &changes[*i]))
+            {
+                let change = &changes[started];
+                // stop between changes, so the index stays consistent with the target
+                let out_of_time = apply_settings
+                    .max_duration
+                    .is_some_and(|max_duration| start.elapsed() >= max_duration);
+                let out_of_transfer = if let IndexChange::AddFile(..) | IndexChange::UpdateFile(..) =
+                    change
+                    && let Some(max_transfer) = apply_settings.max_transfer
+                {
+                    bytes_transferred > 0
+                        && bytes_transferred + change.transfer_size() > max_transfer
                 } else {
-                    true
+                    false
                 };
-                if ok {
-                    *failures -= 1;
-                    let index_file = index.join(file);
-                    if let Some(p) = index_file.parent() {
-                        if let Err(e) = std::env::set_current_dir(&p) {
-                            eprintln!("\n[warn] couldn't cd to {p:?}: {e}");
-                        } else {
-                            let index_file_name = index_file
-                                .file_name()
-                                .expect("a file should always have a filename");
-                            let _ = std::fs::remove_file(&index_file_name);
-                            if let Err(e) = std::os::unix::fs::symlink(link_target, index_file_name)
-                            {
-                                eprintln!(
-                                    "\n[warn] couldn't set index file {index_file:?} to be a symlink to {link_target:?}: {e}"
-                                );
-                            }
-                        }
+                if out_of_time || out_of_transfer {
+                    let limit = if out_of_time {
+                        "max-duration"
                     } else {
-                        eprintln!(
-                            "\n[warn] couldn't get parent for index file's path, so could not create the symlink"
-                        );
-                    }
-                }
-                if let Err(e) = std::env::set_current_dir(&cwd) {
-                    eprintln!("\n[err] fatal: couldn't cd back to {cwd:?}: {e}");
-                    return;
+                        "max-transfer"
+                    };
+                    eprintln!(
+                        "\n[info] stopping after {started} changes, because --{limit} was reached."
+                    );
+                    stopped = true;
+                    break;
                 }
-            }
-            IndexChange::RemoveFile(file) => {
-                let i = index.join(file);
-                let ok = if let Some(target) = target {
-                    let t = target.join(file);
-                    if let Err(e) = fs::remove_file(&t) {
-                        eprintln!(
-                            "\n[warn] couldn't remove file {t:?}, keeping index file {i:?}: {e:?}\n     If this error keeps appearing, check if the file was deleted on the target system but still exists in the index. if yes, consider manually deleting it."
-                        );
-                        false
-                    } else {
-                        true
+                bytes_transferred += change.transfer_size();
+                match change {
+                    IndexChange::AddFile(file, _) => {
+                        write_pending(&mut pending, &pending_path, Pending::COPYING, file)
                     }
-                } else {
-                    true
-                };
-                if ok {
-                    *failures -= 1;
-                    if let Err(e) = fs::remove_file(&i) {
-                        eprintln!("\n[warn] couldn't remove index file {i:?}: {e:?}");
+                    IndexChange::UpdateFile(file, _, _, _) => {
+                        write_pending(&mut pending, &pending_path, Pending::UPDATING, file)
                     }
+                    _ => {}
                 }
+                job_sender
+                    .send(started)
+                    .expect("workers run until the job channel is closed");
+                in_progress.push(started);
+                started += 1;
             }
-            IndexChange::RemoveDir(dir) => {
-                let i = index.join(dir);
-                let ok = if let Some(target) = target {
-                    let t = target.join(dir);
-                    if let Err(e) = fs::remove_dir_all(&t) {
-                        eprintln!(
-                            "\n[warn] couldn't remove directory {t:?}, keeping index files under {i:?}: {e:?}\n     If this error keeps appearing, check if the directory was deleted on the target system but still exists in the index. if yes, consider manually deleting it."
-                        );
-                        false
-                    } else {
-                        true
-                    }
-                } else {
-                    true
-                };
-                if ok {
-                    *failures -= 1;
-                    if let Err(e) = fs::remove_dir_all(&i) {
-                        eprintln!("\n[warn] couldn't remove index directory {i:?}: {e:?}");
-                    }
-                }
+            if in_progress.is_empty() {
+                break;
             }
-        }
-        {
+            let (i, outcome, blocks) = result_receiver
+                .recv()
+                .expect("workers run until the job channel is closed");
+            in_progress.retain(|j| *j != i);
+            let change = &changes[i];
+            if !matches!(outcome, Outcome::Failed) {
+                *failures -= 1;
+                apply_to_index(index, change, matches!(outcome, Outcome::Done), blocks);
+            }
+            gib_transferred += change.transfer_size() as f64 / (1024 * 1024 * 1024) as f64;
+            // failed copies may have left a temporary file or a partially updated file
+            if let IndexChange::AddFile(file, _) | IndexChange::UpdateFile(file, _, _, _) = change
+                && !matches!(outcome, Outcome::Failed)
+            {
+                write_pending(&mut pending, &pending_path, Pending::DONE, file);
+            }
+            if let Err(e) = index.save_periodically() {
+                eprintln!("\n[warn] couldn't save index {:?}: {e}", index.path());
+            }
+            changes_applied += 1;
             eprint_status(
-                i + 1,
+                changes_applied,
                 changes_total,
                 gib_transferred,
                 gib_total,
@@ -281,5 +371,607 @@ pub fn apply_indexchanges_int(
                 gib_len_width,
             );
         }
+        // the workers stop once there are no more jobs
+        drop(job_sender);
+        // failed or skipped changes may have left temporary or partially updated files
+        if pending.is_some_and(|pending| pending.unfinished.is_empty()) {
+            let _ = fs::remove_file(&pending_path);
+        }
+        changes_total - started
+    })
+}
+
+/// If the list of pending files can't be written, it is removed and not used anymore
+fn write_pending(pending: &mut Option<Pending>, path: &Path, state: u8, rel_path: &Path) {
+    if let Some(p) = pending
+        && let Err(e) = p.write(state, rel_path)
+    {
+        eprintln!(
+            "\n[warn] couldn't write to {path:?}: {e}\n       If the backup is interrupted, temporary files may be left on the target."
+        );
+        *pending = None;
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// The modification times recorded for the directories of files which are copied.
+/// Copying a file renames it into its directory, which changes the directory's modification time,
+/// so it is restored afterwards. If the directory's metadata changed, it is updated after its contents anyway.
+fn recorded_dir_times(index: &Index, changes: &[IndexChange]) -> HashMap<PathBuf, (i64, i64)> {
+    let mut dir_times = HashMap::new();
+    for change in changes {
+        if let IndexChange::AddFile(file, _) | IndexChange::UpdateFile(file, _, _, _) = change
+            && let Some(dir) = file.parent()
+            && !dir_times.contains_key(dir)
+            && let Ok(Some(IndexFile {
+                last_modified: Some(secs),
+                last_modified_nanos,
+                ..
+            })) = index.dir_metadata(dir)
+        {
+            let nanos = last_modified_nanos.unwrap_or(0);
+            dir_times.insert(dir.to_owned(), (secs as i64, nanos as i64));
+        }
+    }
+    dir_times
+}
+
+/// Restores the modification time of the directory of a file which was copied, see `recorded_dir_times`
+fn restore_dir_modified(
+    target: &Path,
+    change: &IndexChange,
+    dir_times: &HashMap<PathBuf, (i64, i64)>,
+) {
+    if let IndexChange::AddFile(file, _) | IndexChange::UpdateFile(file, _, _, _) = change
+        && let Some(dir) = file.parent()
+        && let Some(&(secs, nanos)) = dir_times.get(dir)
+    {
+        let t = target.join(dir);
+        if let Err(e) = set_modified(&t, secs, nanos) {
+            eprintln!("\n[warn] couldn't restore the modification time of {t:?}: {e}");
+        }
+    }
+}
+
+/// True if `change` affects the same path as `other`, one of its parent directories, or something in it
+fn depends_on(change: &IndexChange, other: &IndexChange) -> bool {
+    change.paths().iter().any(|path| {
+        other
+            .paths()
+            .iter()
+            .any(|other| path.starts_with(other) || other.starts_with(path))
+    })
+}
+
+/// Applies the change to the target. Errors are logged here.
+/// If the blocks of a file were hashed while copying it, their hashes are stored in `blocks`.
+fn apply_to_target(
+    source: &Source,
+    target: &Path,
+    index_path: &Path,
+    change: &IndexChange,
+    apply_settings: &ApplySettings,
+    damaged: &HashSet<PathBuf>,
+    blocks: &mut Option<BlockHashes>,
+) -> Outcome {
+    match change {
+        IndexChange::AddDir(dir, make_new, _) => {
+            if *make_new {
+                let t = target.join(dir);
+                if let Err(e) = fs::create_dir_all(&t) {
+                    eprintln!("\n[warn] couldn't create directory {t:?}: {e}");
+                    return Outcome::Failed;
+                }
+            }
+            Outcome::Done
+        }
+        IndexChange::AddFile(file, index_file)
+        | IndexChange::UpdateFile(file, index_file, _, _) => {
+            let t = target.join(file);
+            let Some(s) = source.path(file) else {
+                eprintln!("\n[warn] couldn't copy {t:?}, because {file:?} isn't in any source");
+                return Outcome::Failed;
+            };
+            let result = match change {
+                // only if `t` is still the file which the old hashes belong to
+                IndexChange::UpdateFile(_, _, old, _)
+                    if !damaged.contains(file)
+                        && fs::symlink_metadata(&t)
+                            .is_ok_and(|m| m.is_file() && m.len() == old.size) =>
+                {
+                    update_in_place(&s, &t, index_file, old, blocks)
+                }
+                _ => copy_atomically(&s, &t, index_file, apply_settings, blocks),
+            };
+            match result {
+                Err(e) => {
+                    eprintln!("\n[warn] couldn't copy file from {s:?} to {t:?}: {e}");
+                    Outcome::Failed
+                }
+                Ok(Err(e)) => {
+                    eprintln!(
+                        "\n[warn] copied {t:?}, but couldn't set its permissions, owner or modification time: {e}"
+                    );
+                    Outcome::MetadataFailed
+                }
+                Ok(Ok(())) => Outcome::Done,
+            }
+        }
+        IndexChange::AddSpecial(file, index_file) => {
+            let t = target.join(file);
+            let _ = fs::remove_file(&t);
+            if let Err(e) = make_special(&t, index_file) {
+                eprintln!("\n[warn] couldn't create special file {t:?}: {e}");
+                Outcome::Failed
+            } else if let Err(e) = set_metadata(&t, index_file) {
+                eprintln!(
+                    "\n[warn] created {t:?}, but couldn't set its permissions, owner or modification time: {e}"
+                );
+                Outcome::MetadataFailed
+            } else {
+                Outcome::Done
+            }
+        }
+        IndexChange::AddSymlink(file, link_target) => {
+            // the link is created as it is, relative links are relative to its directory
+            let t = target.join(file);
+            let _ = fs::remove_file(&t);
+            if let Err(e) = std::os::unix::fs::symlink(link_target, &t) {
+                eprintln!(
+                    "\n[warn] couldn't set file {t:?} to be a symlink to {link_target:?}: {e}"
+                );
+                Outcome::Failed
+            } else {
+                Outcome::Done
+            }
+        }
+        IndexChange::Move(from, to) => {
+            let (f, t) = (target.join(from), target.join(to));
+            let result = match t.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|()| fs::rename(&f, &t));
+            if let Err(e) = result {
+                eprintln!("\n[warn] couldn't move {f:?} to {t:?}: {e}");
+                Outcome::Failed
+            } else {
+                Outcome::Done
+            }
+        }
+        IndexChange::RemoveFile(file) => {
+            let t = target.join(file);
+            if let Err(e) = fs::remove_file(&t) {
+                let i = index_path.join(file);
+                eprintln!(
+                    "\n[warn] couldn't remove file {t:?}, keeping index file {i:?}: {e:?}\n     If this error keeps appearing, check if the file was deleted on the target system but still exists in the index. if yes, consider manually deleting it."
+                );
+                Outcome::Failed
+            } else {
+                Outcome::Done
+            }
+        }
+        IndexChange::RemoveDir(dir) => {
+            let t = target.join(dir);
+            if let Err(e) = fs::remove_dir_all(&t) {
+                let i = index_path.join(dir);
+                eprintln!(
+                    "\n[warn] couldn't remove directory {t:?}, keeping index files under {i:?}: {e:?}\n     If this error keeps appearing, check if the directory was deleted on the target system but still exists in the index. if yes, consider manually deleting it."
+                );
+                Outcome::Failed
+            } else {
+                Outcome::Done
+            }
+        }
+        IndexChange::UpdateMetadata(path, _, index_file) => {
+            let t = target.join(path);
+            if let Err(e) = set_metadata(&t, index_file) {
+                eprintln!(
+                    "\n[warn] couldn't set permissions, owner or modification time of {t:?}: {e}"
+                );
+                Outcome::Failed
+            } else {
+                Outcome::Done
+            }
+        }
+    }
+}
+
+/// Saves a change which was applied to the target to the index. Errors are logged here.
+/// `blocks` are the hashes of a file's blocks, if they were hashed while copying it.
+fn apply_to_index(
+    index: &mut Index,
+    change: &IndexChange,
+    metadata_ok: bool,
+    blocks: Option<BlockHashes>,
+) {
+    match change {
+        IndexChange::AddDir(dir, make_new, _) => {
+            if *make_new && let Err(e) = index.create_dir(dir) {
+                let t = index.path().join(dir);
+                eprintln!("\n[warn] couldn't create index directory {t:?}: {e}");
+            }
+        }
+        IndexChange::AddFile(file, index_file)
+        | IndexChange::UpdateFile(file, index_file, _, _) => {
+            let index_file = IndexFile {
+                blocks,
+                ..index_file.clone()
+            };
+            write_index_file(index, file, &index_file, metadata_ok);
+        }
+        IndexChange::AddSpecial(file, index_file) => {
+            write_index_file(index, file, index_file, metadata_ok);
+        }
+        IndexChange::AddSymlink(file, link_target) => {
+            if let Err(e) = index.write_symlink(file, link_target) {
+                let index_file = index.path().join(file);
+                eprintln!(
+                    "\n[warn] couldn't set index file {index_file:?} to be a symlink to {link_target:?}: {e}"
+                );
+            }
+        }
+        IndexChange::Move(from, to) => {
+            let result = match to.parent() {
+                Some(parent) => index.create_dir(parent),
+                None => Ok(()),
+            }
+            .and_then(|()| index.rename(from, to));
+            if let Err(e) = result {
+                let (f, t) = (index.path().join(from), index.path().join(to));
+                eprintln!("\n[warn] couldn't move index entry {f:?} to {t:?}: {e}");
+            }
+        }
+        IndexChange::RemoveFile(file) => {
+            if let Err(e) = index.remove_file(file) {
+                let i = index.path().join(file);
+                eprintln!("\n[warn] couldn't remove index file {i:?}: {e:?}");
+            }
+        }
+        IndexChange::RemoveDir(dir) => {
+            if let Err(e) = index.remove_dir(dir) {
+                let i = index.path().join(dir);
+                eprintln!("\n[warn] couldn't remove index directory {i:?}: {e:?}");
+            }
+        }
+        IndexChange::UpdateMetadata(path, is_dir, index_file) => {
+            let result = if *is_dir {
+                index.write_dir_metadata(path, index_file)
+            } else {
+                index.write_file(path, index_file)
+            };
+            if let Err(e) = result {
+                let i = index.path().join(path);
+                eprintln!("\n[warn] couldn't save index file {i:?}: {e}");
+            }
+        }
+    }
+}
+
+/// Copies the file to a temporary file next to `t`, which then replaces `t`,
+/// so `t` is never a partial copy, even if the backup is interrupted.
+/// If the copy is resumable (see `--resume-min-size`), the temporary file is kept if it fails, so the next backup can continue it.
+/// Large files (see `--delta-min-size`) are hashed while copying them, and the hashes are stored in `blocks`.
+/// The inner result is the result of setting the metadata, which doesn't prevent the copy.
+fn copy_atomically(
+    s: &Path,
+    t: &Path,
+    index_file: &IndexFile,
+    apply_settings: &ApplySettings,
+    blocks: &mut Option<BlockHashes>,
+) -> io::Result<io::Result<()>> {
+    let tmp = temp_path(t);
+    let resumable = index_file.size >= apply_settings.resume_min_size;
+    let mut hasher =
+        (index_file.size >= apply_settings.delta_min_size).then(delta::BlockHasher::default);
+    let copied = if resumable {
+        resume::copy_resumable(s, &tmp, index_file, hasher.as_mut())
+    } else if let Some(hasher) = &mut hasher {
+        fs::File::open(s).and_then(|mut source| {
+            let mut writer = delta::HashingWriter {
+                inner: fs::File::create(&tmp)?,
+                hasher: Some(hasher),
+            };
+            io::copy(&mut source, &mut writer).map(|_| ())
+        })
+    } else {
+        fs::copy(s, &tmp).map(|_| ())
+    };
+    let result = copied.and_then(|()| {
+        let metadata_result = set_metadata(&tmp, index_file);
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, t)?;
+        Ok(metadata_result)
+    });
+    if resumable {
+        if result.is_ok() {
+            let _ = fs::remove_file(resume::progress_path(&tmp));
+        }
+    } else if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    if result.is_ok() {
+        *blocks = hasher.map(delta::BlockHasher::finish);
+    }
+    result
+}
+
+/// Updates `t`, which is still the `old` file, by only writing the blocks which changed.
+/// The hashes of the new blocks are stored in `blocks`.
+/// The inner result is the result of setting the metadata, which doesn't prevent the update.
+fn update_in_place(
+    s: &Path,
+    t: &Path,
+    index_file: &IndexFile,
+    old: &IndexFile,
+    blocks: &mut Option<BlockHashes>,
+) -> io::Result<io::Result<()>> {
+    let old_blocks = old
+        .blocks
+        .as_ref()
+        .expect("files are only updated in place if their blocks were hashed");
+    *blocks = Some(delta::update_in_place(s, t, old_blocks)?);
+    Ok(set_metadata(t, index_file))
+}
+
+/// The temporary file used while copying to `t`.
+/// Its name is reserved, so it is never part of the backup, and only depends on `t`'s name,
+/// so a copy can't use another copy's temporary file.
+fn temp_path(t: &Path) -> PathBuf {
+    let name = t.file_name().unwrap_or_default().as_bytes();
+    let hash = blake3::hash(name).to_hex();
+    t.with_file_name(format!("{RESERVED_PREFIX}tmp_{}", &hash[..16]))
+}
+
+/// Lists the files which are being copied to temporary files or updated in place on the target,
+/// so the temporary files can be removed after a backup was interrupted,
+/// and partially updated files can be copied again.
+/// Each entry is `COPYING`, `UPDATING` or `DONE`, followed by the path and a null byte.
+struct Pending {
+    file: fs::File,
+    /// the entries which aren't `DONE`
+    unfinished: HashSet<PathBuf>,
+}
+
+impl Pending {
+    const COPYING: u8 = b'+';
+    const UPDATING: u8 = b'*';
+    const DONE: u8 = b'-';
+    /// Removes the temporary files which an interrupted backup left on the target,
+    /// then starts a new list.
+    /// Partial copies of the `resumable` files are kept, so they can be continued.
+    /// Files which were being updated in place are added to `damaged`, and stay in the list until they were copied again.
+    fn open(
+        path: &Path,
+        target: &Path,
+        resumable: &HashSet<&Path>,
+        damaged: &mut HashSet<PathBuf>,
+    ) -> io::Result<Self> {
+        let mut kept = vec![];
+        match fs::read(path) {
+            Ok(bytes) => {
+                let mut unfinished = HashMap::new();
+                for entry in bytes.split(|b| *b == 0).filter(|e| !e.is_empty()) {
+                    let rel_path = Path::new(OsStr::from_bytes(&entry[1..]));
+                    if entry[0] == Self::DONE {
+                        unfinished.remove(rel_path);
+                    } else {
+                        unfinished.insert(rel_path, entry[0]);
+                    }
+                }
+                for (rel_path, state) in unfinished {
+                    if state == Self::UPDATING {
+                        damaged.insert(rel_path.to_owned());
+                    }
+                    if state == Self::UPDATING || resumable.contains(rel_path) {
+                        kept.push((rel_path.to_owned(), state));
+                    }
+                    if resumable.contains(rel_path) {
+                        continue;
+                    }
+                    let tmp = temp_path(&target.join(rel_path));
+                    let _ = fs::remove_file(resume::progress_path(&tmp));
+                    match fs::remove_file(&tmp) {
+                        Ok(()) => eprintln!(
+                            "[info] removed {tmp:?}, which was left by an interrupted backup."
+                        ),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => eprintln!("[warn] couldn't remove {tmp:?}: {e}"),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut pending = Self {
+            file: fs::File::create(path)?,
+            unfinished: HashSet::new(),
+        };
+        for (rel_path, state) in kept {
+            pending.write(state, &rel_path)?;
+        }
+        Ok(pending)
+    }
+    fn write(&mut self, state: u8, rel_path: &Path) -> io::Result<()> {
+        let mut entry = vec![state];
+        entry.extend_from_slice(rel_path.as_os_str().as_bytes());
+        entry.push(0);
+        self.file.write_all(&entry)?;
+        if state == Self::DONE {
+            self.unfinished.remove(rel_path);
+        } else {
+            self.unfinished.insert(rel_path.to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// If the metadata couldn't be set on the target, it is left out of the index,
+/// so it will be updated during the next backup.
+fn write_index_file(index: &mut Index, file: &Path, index_file: &IndexFile, metadata_ok: bool) {
+    let result = if metadata_ok {
+        index.write_file(file, index_file)
+    } else {
+        index.write_file(
+            file,
+            &IndexFile {
+                mode: None,
+                uid: None,
+                gid: None,
+                ..index_file.clone()
+            },
+        )
+    };
+    if let Err(e) = result {
+        let t = index.path().join(file);
+        eprintln!("\n[warn] couldn't save index file {t:?}: {e}");
+    }
+}
+
+/// Creates a fifo, socket or device node using mknod
+fn make_special(path: &Path, index_file: &IndexFile) -> io::Result<()> {
+    let Some(special) = index_file.special else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a special file",
+        ));
+    };
+    let file_type = match special {
+        SpecialFile::Fifo => libc::S_IFIFO,
+        SpecialFile::Socket => libc::S_IFSOCK,
+        SpecialFile::CharDevice(_) => libc::S_IFCHR,
+        SpecialFile::BlockDevice(_) => libc::S_IFBLK,
+    };
+    // the exact permissions are set by `set_metadata`
+    let mode = file_type | (index_file.mode.unwrap_or(0o600) & 0o777) as libc::mode_t;
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(path.as_ptr(), mode, special.rdev() as libc::dev_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the permissions, owner and modification time of a file or directory
+/// to the ones in `index_file`, if they are known and different.
+/// The owner is only changed when running as root, and only if the target allows it.
+fn set_metadata(path: &Path, index_file: &IndexFile) -> io::Result<()> {
+    static CHOWN_DENIED: AtomicBool = AtomicBool::new(false);
+    let metadata = fs::symlink_metadata(path)?;
+    let chown = unsafe { libc::geteuid() } == 0
+        && !CHOWN_DENIED.load(atomic::Ordering::Relaxed)
+        && (index_file.uid.is_some_and(|uid| uid != metadata.uid())
+            || index_file.gid.is_some_and(|gid| gid != metadata.gid()));
+    let chown = chown
+        && match std::os::unix::fs::chown(path, index_file.uid, index_file.gid) {
+            Ok(()) => true,
+            // for example NFS with root_squash
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                if !CHOWN_DENIED.swap(true, atomic::Ordering::Relaxed) {
+                    eprintln!(
+                        "\n[warn] couldn't change the owner of {path:?}: {e}\n       The owners of files on the target won't be changed."
+                    );
+                }
+                false
+            }
+            Err(e) => return Err(e),
+        };
+    let modified = (metadata.mtime(), metadata.mtime_nsec());
+    if let Some(secs) = index_file.last_modified
+        && modified != (secs as i64, index_file.last_modified_nanos.unwrap_or(0) as i64)
+    {
+        set_modified(
+            path,
+            secs as i64,
+            index_file.last_modified_nanos.unwrap_or(0) as i64,
+        )?;
+    }
+    if let Some(mode) = index_file.mode
+        && (chown || mode != metadata.mode() & 0o7777)
+    {
+        // do this after chown, which may remove the setuid and setgid bits
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Sets the modification time of a file or directory
+fn set_modified(path: &Path, secs: i64, nanos: i64) -> io::Result<()> {
+    // not `File::set_modified`, because opening a fifo would block
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: secs as libc::time_t,
+            tv_nsec: nanos as _,
+        },
+    ];
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        index::IndexFormat,
+        update_index::{Settings, perform_index_diff},
+    };
+
+    fn backup(source: &Source, index: &mut Index, target: &Path) -> Vec<IndexChange> {
+        let diff = perform_index_diff(
+            source,
+            index,
+            Some(target),
+            crate::config::Ignore(vec![]),
+            &Settings::default(),
+            None,
+        )
+        .unwrap();
+        let apply_settings = ApplySettings {
+            apply_threads: 1,
+            resume_min_size: u64::MAX,
+            delta_min_size: u64::MAX,
+            max_transfer: None,
+            max_duration: None,
+        };
+        let target = Some(target.to_owned());
+        let applied =
+            apply_indexchanges(source, index, &target, &diff.changes, None, &apply_settings);
+        assert_eq!(applied.failures, 0);
+        diff.changes
+    }
+
+    #[test]
+    fn update_keeps_directory_mtime() {
+        let dir = std::env::temp_dir().join(format!("rembackup-test-{}-mtime", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (s, t) = (dir.join("source"), dir.join("target"));
+        fs::create_dir_all(s.join("d")).unwrap();
+        fs::write(s.join("d/file"), "old").unwrap();
+        let source = Source::Dir(s.clone());
+        let mut index = Index::open(&dir.join("index"), Some(IndexFormat::Tree)).unwrap();
+        backup(&source, &mut index, &t);
+
+        fs::write(s.join("d/file"), "new contents").unwrap();
+        let changes = backup(&source, &mut index, &t);
+        assert!(
+            matches!(changes[..], [.., IndexChange::AddFile(..)]),
+            "{changes:?}"
+        );
+        let recorded = index.dir_metadata(Path::new("d")).unwrap().unwrap();
+        let d = fs::metadata(t.join("d")).unwrap();
+        assert_eq!(
+            (d.mtime(), d.mtime_nsec()),
+            (
+                recorded.last_modified.unwrap() as i64,
+                recorded.last_modified_nanos.unwrap_or(0) as i64
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,8 +1,14 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::update_index::Settings;
+use crate::{
+    apply_indexchanges::ApplySettings,
+    guard::DeleteLimits,
+    index::IndexFormat,
+    source::{Source, named_sources, parse_named_source},
+    update_index::Settings,
+};
 
 /// rembackup,
 /// a simple backup tool for local or remote backups.
@@ -10,25 +16,382 @@ use crate::update_index::Settings;
 ///
 /// rembackup copies files from <source> to <target> using and storing information in <index>.
 #[derive(Parser)]
-#[command(author, version)]
+#[command(
+    author,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    override_usage = "rembackup [OPTIONS] <SOURCE> <INDEX> [TARGET]
+       rembackup [OPTIONS] --source <NAME=PATH>... --index <INDEX> [--target <TARGET>]
+       rembackup <COMMAND>"
+)]
 pub struct Args {
-    /// the data to be backed up
-    #[arg()]
-    pub source: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub backup: Option<BackupArgs>,
+    // not part of `BackupArgs`, because clap can't tell if an optional flattened struct
+    // was used if it contains another flattened struct
+    #[command(flatten)]
+    pub settings: Settings,
+    #[command(flatten)]
+    pub apply_settings: ApplySettings,
+    #[command(flatten)]
+    pub delete_limits: DeleteLimits,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// find the changes a backup would make, like running without a subcommand,
+    /// but save them to <plan> instead of applying them.
+    #[command(
+        override_usage = "rembackup plan [OPTIONS] --output <OUTPUT> <SOURCE> <INDEX> [TARGET]
+       rembackup plan [OPTIONS] --output <OUTPUT> --source <NAME=PATH>... --index <INDEX> [--target <TARGET>]"
+    )]
+    Plan(PlanArgs),
+    /// apply the changes saved by `rembackup plan`.
+    ///
+    /// fails if the index was changed after the plan was made.
+    Apply(ApplyArgs),
+    /// check if <target> contains what <index> says it does.
+    ///
+    /// reports files which are missing, have the wrong size or timestamp,
+    /// or which exist on the target but not in the index.
+    Verify(VerifyArgs),
+    /// create a new index from an existing backup, for example if the index was lost.
+    ///
+    /// entries which are the same in <source> and <target> are added to the index,
+    /// so the next backup only copies what is actually different.
+    #[command(
+        override_usage = "rembackup rebuild-index [OPTIONS] <SOURCE> <INDEX> <TARGET>
+       rembackup rebuild-index [OPTIONS] --source <NAME=PATH>... --index <INDEX> --target <TARGET>"
+    )]
+    RebuildIndex(RebuildIndexArgs),
+    /// back up <source> like running without a subcommand, then keep watching it for changes.
+    ///
+    /// changed directories are found using inotify and backed up after --quiet-period,
+    /// without walking the entire source again. runs until it is stopped.
+    #[command(override_usage = "rembackup watch [OPTIONS] <SOURCE> <INDEX> [TARGET]
+       rembackup watch [OPTIONS] --source <NAME=PATH>... --index <INDEX> [--target <TARGET>]")]
+    Watch(WatchArgs),
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// human-readable text
+    #[default]
+    Text,
+    /// JSON lines
+    Json,
+}
+
+#[derive(clap::Args)]
+pub struct BackupArgs {
+    /// the data to be backed up.
+    /// leave this out if you use --source.
+    #[arg(required_unless_present = "sources", conflicts_with = "sources")]
+    pub source: Option<PathBuf>,
     /// the index used to determine which files have been modified
-    #[arg()]
-    pub index: PathBuf,
+    #[arg(required_unless_present = "sources", conflicts_with = "sources")]
+    pub index: Option<PathBuf>,
     /// where your backup will be stored
-    #[arg()]
+    #[arg(conflicts_with = "sources")]
     pub target: Option<PathBuf>,
+    /// back up several directories at once, each to a sub-directory <NAME> of the index and target.
+    /// can be used multiple times, and replaces all positional arguments,
+    /// like `--source docs=~/docs --source etc=/etc --index ~/index --target /mnt/backup`.
+    #[arg(long = "source", value_name = "NAME=PATH", value_parser = parse_named_source, requires = "named_index")]
+    pub sources: Vec<(String, PathBuf)>,
+    /// the index, when using --source
+    #[arg(long = "index", value_name = "INDEX", conflicts_with = "source")]
+    pub named_index: Option<PathBuf>,
+    /// the target, when using --source
+    #[arg(long = "target", value_name = "TARGET", conflicts_with = "source")]
+    pub named_target: Option<PathBuf>,
     /// don't ask for confirmation, just apply the changes.
     #[arg(long)]
     pub noconfirm: bool,
+    /// use <target> even if it contains a backup made with another index (or none),
+    /// for example after copying the backup without its `.rembackup_target` file.
+    #[arg(long)]
+    pub adopt_target: bool,
 
     /// the file in which you specified what files/directories should be ignored
     #[arg(long)]
     pub ignore: Option<PathBuf>,
 
+    /// how the index is stored: `tree` uses one file per backed up file, `packed` a single file.
+    /// if the index already exists in the other format, it will be converted.
+    /// [default: the format of the existing index, or `tree`]
+    #[arg(long, value_enum)]
+    pub index_format: Option<IndexFormat>,
+}
+
+impl BackupArgs {
+    /// The source, index and target.
+    /// If there are named sources, the index and target are the ones given by --index and --target.
+    pub fn paths(&self) -> Result<(Source, PathBuf, Option<PathBuf>), String> {
+        if let (Some(source), Some(index)) = (&self.source, &self.index) {
+            return Ok((
+                Source::Dir(source.clone()),
+                index.clone(),
+                self.target.clone(),
+            ));
+        }
+        let index = self
+            .named_index
+            .clone()
+            .expect("clap requires --index with --source");
+        Ok((
+            named_sources(self.sources.clone())?,
+            index,
+            self.named_target.clone(),
+        ))
+    }
+}
+
+#[derive(clap::Args)]
+pub struct PlanArgs {
+    /// where the plan will be saved
+    #[arg(long, short)]
+    pub output: PathBuf,
+    #[command(flatten)]
+    pub backup: BackupArgs,
+    #[command(flatten)]
+    pub settings: Settings,
+    #[command(flatten)]
+    pub delete_limits: DeleteLimits,
+}
+
+#[derive(clap::Args)]
+pub struct WatchArgs {
+    /// how long to wait after the last change before backing up the changed directories
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub quiet_period: Duration,
+    #[command(flatten)]
+    pub backup: BackupArgs,
     #[command(flatten)]
     pub settings: Settings,
+    /// --max-transfer and --max-duration apply to each backup separately
+    #[command(flatten)]
+    pub apply_settings: ApplySettings,
+    #[command(flatten)]
+    pub delete_limits: DeleteLimits,
+}
+
+#[derive(clap::Args)]
+pub struct ApplyArgs {
+    /// the plan created by `rembackup plan`
+    #[arg()]
+    pub plan: PathBuf,
+    /// use this source instead of the one saved in the plan, for example on another machine.
+    /// if the plan has multiple named sources, use `--source NAME=PATH` for each source you want to replace.
+    #[arg(long, value_name = "[NAME=]PATH")]
+    pub source: Vec<PathBuf>,
+    /// use this index instead of the one saved in the plan
+    #[arg(long)]
+    pub index: Option<PathBuf>,
+    /// use this target instead of the one saved in the plan
+    #[arg(long)]
+    pub target: Option<PathBuf>,
+    /// use the target even if it contains a backup made with another index, see the main command's --adopt-target
+    #[arg(long)]
+    pub adopt_target: bool,
+    #[command(flatten)]
+    pub apply_settings: ApplySettings,
+}
+
+#[derive(clap::Args)]
+pub struct VerifyArgs {
+    /// the index used by the backup
+    #[arg()]
+    pub index: PathBuf,
+    /// where the backup is stored
+    #[arg()]
+    pub target: PathBuf,
+    /// remove broken entries from the index, so they will be copied again during the next backup.
+    /// entries with the wrong type are recorded as they are on the target, so the next backup replaces them.
+    /// files which are on the target but not in the index will not be changed.
+    #[arg(long)]
+    pub repair: bool,
+    #[command(flatten)]
+    pub timestamps: TimestampArgs,
+}
+
+#[derive(clap::Args)]
+pub struct RebuildIndexArgs {
+    /// the data which was backed up.
+    /// leave this out if you use --source.
+    #[arg(required_unless_present = "sources", conflicts_with = "sources")]
+    pub source: Option<PathBuf>,
+    /// where the new index will be created
+    #[arg(required_unless_present = "sources", conflicts_with = "sources")]
+    pub index: Option<PathBuf>,
+    /// where the backup is stored
+    #[arg(required_unless_present = "sources", conflicts_with = "sources")]
+    pub target: Option<PathBuf>,
+    /// the named sources which were backed up, see the main command's --source.
+    /// replaces all positional arguments, like `--source docs=~/docs --source etc=/etc --index ~/index --target /mnt/backup`.
+    #[arg(long = "source", value_name = "NAME=PATH", value_parser = parse_named_source, requires_all = ["named_index", "named_target"])]
+    pub sources: Vec<(String, PathBuf)>,
+    /// the index, when using --source
+    #[arg(long = "index", value_name = "INDEX", conflicts_with = "source")]
+    pub named_index: Option<PathBuf>,
+    /// the target, when using --source
+    #[arg(long = "target", value_name = "TARGET", conflicts_with = "source")]
+    pub named_target: Option<PathBuf>,
+    /// compare the contents of files instead of their timestamps.
+    /// this reads every file in both source and target, which can be slow.
+    #[arg(long)]
+    pub compare_content: bool,
+    #[command(flatten)]
+    pub timestamps: TimestampArgs,
+    /// how the new index is stored, see the main command's --index-format
+    #[arg(long, value_enum, default_value = "tree")]
+    pub index_format: IndexFormat,
+}
+
+impl RebuildIndexArgs {
+    /// The source, index and target, like `BackupArgs::paths`
+    pub fn paths(&self) -> Result<(Source, PathBuf, PathBuf), String> {
+        if let (Some(source), Some(index), Some(target)) = (&self.source, &self.index, &self.target)
+        {
+            return Ok((Source::Dir(source.clone()), index.clone(), target.clone()));
+        }
+        let (Some(index), Some(target)) = (&self.named_index, &self.named_target) else {
+            unreachable!("clap requires --index and --target with --source");
+        };
+        Ok((
+            named_sources(self.sources.clone())?,
+            index.clone(),
+            target.clone(),
+        ))
+    }
+}
+
+/// Options for commands which compare timestamps, but don't use all the `Settings`
+#[derive(clap::Args)]
+pub struct TimestampArgs {
+    /// don't compare timestamps, only sizes
+    #[arg(long)]
+    pub ignore_timestamp: bool,
+    /// treat timestamps as equal if they differ by less than this, like `2s` for FAT filesystems.
+    #[arg(long, value_parser = parse_duration, default_value = "0s")]
+    pub timestamp_granularity: Duration,
+}
+impl TimestampArgs {
+    pub fn settings(&self) -> Settings {
+        Settings {
+            ignore_timestamp: self.ignore_timestamp,
+            timestamp_granularity: self.timestamp_granularity,
+            ..Default::default()
+        }
+    }
+}
+
+/// Parses durations like `2s`, `1.5h` or `100ms`
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .ok_or_else(|| format!("{text:?} has no unit, expected one of ns, us, ms, s, m, h, d"))?;
+    let (number, unit) = text.split_at(unit_start);
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("couldn't parse {number:?} as a number: {e}"))?;
+    let unit_secs = match unit.trim() {
+        "ns" => 1e-9,
+        "us" => 1e-6,
+        "ms" => 1e-3,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        "d" => 24.0 * 60.0 * 60.0,
+        unit => {
+            return Err(format!(
+                "unknown unit {unit:?}, expected one of ns, us, ms, s, m, h, d"
+            ));
+        }
+    };
+    Duration::try_from_secs_f64(number * unit_secs).map_err(|e| e.to_string())
+}
+
+/// Parses sizes like `500MB`, `50GiB` or `1.5T`.
+/// `K`, `M`, `G` and `T` are the same as `KiB`, `MiB`, `GiB` and `TiB`.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(unit_start);
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("couldn't parse {number:?} as a number: {e}"))?;
+    let unit_bytes: f64 = match unit.trim() {
+        "" | "B" => 1.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "K" | "KiB" => 1024.0,
+        "M" | "MiB" => 1024.0 * 1024.0,
+        "G" | "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "T" | "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        unit => {
+            return Err(format!(
+                "unknown unit {unit:?}, expected one of B, KB, MB, GB, TB, KiB, MiB, GiB, TiB"
+            ));
+        }
+    };
+    Ok((number * unit_bytes) as u64)
+}
+
+/// Parses fractions like `0.5` or `50%`
+pub fn parse_fraction(text: &str) -> Result<f64, String> {
+    let text = text.trim();
+    let (number, scale) = match text.strip_suffix('%') {
+        Some(number) => (number.trim(), 0.01),
+        None => (text, 1.0),
+    };
+    let fraction = number
+        .parse::<f64>()
+        .map_err(|e| format!("couldn't parse {number:?} as a number: {e}"))?
+        * scale;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("{text:?} must be between 0 and 1 (or 0% and 100%)"));
+    }
+    Ok(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration(" 1.5h "), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("100ms"), Ok(Duration::from_millis(100)));
+        assert_eq!(parse_duration("3 m"), Ok(Duration::from_secs(180)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("250us"), Ok(Duration::from_micros(250)));
+        assert_eq!(parse_duration("7ns"), Ok(Duration::from_nanos(7)));
+        for invalid in ["", "10", "s", "1.2.3s", "5 years", "-1s"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("500MB"), Ok(500_000_000));
+        assert_eq!(parse_size("50GiB"), Ok(50 << 30));
+        assert_eq!(parse_size("1.5T"), Ok(3 << 39));
+        assert_eq!(parse_size(" 2 K "), Ok(2048));
+        assert_eq!(parse_size("0.5KB"), Ok(500));
+        assert_eq!(parse_size("1B"), Ok(1));
+        for invalid in ["", "MB", "1.2.3", "5 PB", "-1", "1 mb"] {
+            assert!(parse_size(invalid).is_err(), "{invalid:?}");
+        }
+    }
 }
@@ -0,0 +1,274 @@
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    str::FromStr,
+};
+
+/// Large files are split into blocks of this size, and the hash of each block is stored in the index,
+/// so only the blocks which changed have to be written to the target when the file is updated.
+pub const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+/// How many bytes of each block's hash are stored
+const HASH_LEN: usize = 8;
+
+/// The hashes of a file's blocks, as they were written to the target
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockHashes {
+    pub block_size: u64,
+    pub hashes: Vec<[u8; HASH_LEN]>,
+}
+
+impl BlockHashes {
+    /// Reads the entire file
+    pub fn hash_file(path: &Path) -> io::Result<Self> {
+        let mut hasher = BlockHasher::default();
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        Ok(hasher.finish())
+    }
+    /// True if a file with these hashes can be updated in place using `update_in_place`
+    pub fn usable(&self) -> bool {
+        self.block_size == BLOCK_SIZE
+    }
+    /// How many bytes of a file with these hashes (and this `size`) differ from the `old` file
+    pub fn delta_size(&self, size: u64, old: &Self) -> u64 {
+        if self.block_size != old.block_size {
+            return size;
+        }
+        (0..self.hashes.len())
+            .filter(|i| old.hashes.get(*i) != Some(&self.hashes[*i]))
+            .map(|i| self.block_size.min(size - i as u64 * self.block_size))
+            .sum()
+    }
+}
+
+/// `<block size>:<hashes in hex>`
+impl fmt::Display for BlockHashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.block_size)?;
+        for hash in &self.hashes {
+            for byte in hash {
+                write!(f, "{byte:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BlockHashes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block_size, hashes) = s
+            .split_once(':')
+            .ok_or_else(|| format!("no block size in {s:?}"))?;
+        let block_size = block_size
+            .parse()
+            .map_err(|e| format!("invalid block size {block_size:?}: {e}"))?;
+        if hashes.len() % (2 * HASH_LEN) != 0 || !hashes.is_ascii() {
+            return Err(format!("invalid block hashes {hashes:?}"));
+        }
+        let hashes = hashes
+            .as_bytes()
+            .chunks(2 * HASH_LEN)
+            .map(|hex| {
+                let mut hash = [0; HASH_LEN];
+                for (i, byte) in hash.iter_mut().enumerate() {
+                    let hex = std::str::from_utf8(&hex[2 * i..2 * i + 2]).expect("is ascii");
+                    *byte = u8::from_str_radix(hex, 16)
+                        .map_err(|_| format!("invalid block hash {hex:?}"))?;
+                }
+                Ok(hash)
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { block_size, hashes })
+    }
+}
+
+/// Computes the `BlockHashes` of everything written to it
+#[derive(Default)]
+pub struct BlockHasher {
+    hasher: blake3::Hasher,
+    in_block: u64,
+    hashes: Vec<[u8; HASH_LEN]>,
+}
+
+impl BlockHasher {
+    pub fn finish(mut self) -> BlockHashes {
+        if self.in_block > 0 {
+            self.end_block();
+        }
+        BlockHashes {
+            block_size: BLOCK_SIZE,
+            hashes: self.hashes,
+        }
+    }
+    fn end_block(&mut self) {
+        let hash = self.hasher.finalize();
+        self.hashes.push(
+            hash.as_bytes()[..HASH_LEN]
+                .try_into()
+                .expect("hash is long enough"),
+        );
+        self.hasher.reset();
+        self.in_block = 0;
+    }
+}
+
+impl Write for BlockHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min((BLOCK_SIZE - self.in_block) as usize);
+        self.hasher.update(&buf[..len]);
+        self.in_block += len as u64;
+        if self.in_block == BLOCK_SIZE {
+            self.end_block();
+        }
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes to `inner`, and passes everything which was written to the `hasher`
+pub struct HashingWriter<'a, W> {
+    pub inner: W,
+    pub hasher: Option<&'a mut BlockHasher>,
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.write_all(&buf[..len])?;
+        }
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Updates `t`, which must have the `old` hashes, to be a copy of `s` by only writing the blocks which changed.
+/// Reads all of `s`, but nothing from `t`. Returns the hashes of the new contents.
+/// If this is interrupted, `t` is neither the old nor the new file, and has to be copied again.
+pub fn update_in_place(s: &Path, t: &Path, old: &BlockHashes) -> io::Result<BlockHashes> {
+    let mut source = fs::File::open(s)?;
+    let target = fs::OpenOptions::new().write(true).open(t)?;
+    let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+    let mut hashes = vec![];
+    let mut offset = 0;
+    loop {
+        block.clear();
+        (&mut source).take(BLOCK_SIZE).read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        let mut hasher = BlockHasher::default();
+        hasher.write_all(&block)?;
+        let hash = hasher.finish().hashes[0];
+        if !old.usable() || old.hashes.get(hashes.len()) != Some(&hash) {
+            target.write_all_at(&block, offset)?;
+        }
+        hashes.push(hash);
+        offset += block.len() as u64;
+    }
+    target.set_len(offset)?;
+    target.sync_all()?;
+    Ok(BlockHashes {
+        block_size: BLOCK_SIZE,
+        hashes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_hashes_round_trip() {
+        let hashes = BlockHashes {
+            block_size: BLOCK_SIZE,
+            hashes: vec![
+                [0; HASH_LEN],
+                [0xab; HASH_LEN],
+                *b"\x01\x23\x45\x67\x89\xab\xcd\xef",
+            ],
+        };
+        let s = hashes.to_string();
+        assert_eq!(
+            s,
+            format!("{BLOCK_SIZE}:0000000000000000abababababababab0123456789abcdef")
+        );
+        assert_eq!(s.parse::<BlockHashes>(), Ok(hashes));
+        let empty = BlockHashes {
+            block_size: 1,
+            hashes: vec![],
+        };
+        assert_eq!(empty.to_string().parse::<BlockHashes>(), Ok(empty));
+    }
+
+    #[test]
+    fn block_hashes_parse_errors() {
+        for s in [
+            "",
+            "0123456789abcdef",
+            "x:0123456789abcdef",
+            "4096:0123456789abcde",
+            "4096:0123456789abcdeg",
+            "4096:0123456789abcd\u{e9}",
+        ] {
+            assert!(s.parse::<BlockHashes>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn hasher_splits_blocks() {
+        let mut hasher = BlockHasher::default();
+        hasher
+            .write_all(&vec![1; BLOCK_SIZE as usize + 10])
+            .unwrap();
+        let hashes = hasher.finish();
+        assert_eq!(hashes.hashes.len(), 2);
+        assert!(hashes.usable());
+        assert_eq!(BlockHasher::default().finish().hashes.len(), 0);
+    }
+
+    #[test]
+    fn delta_size_counts_changed_blocks() {
+        let old = BlockHashes {
+            block_size: 10,
+            hashes: vec![[1; HASH_LEN], [2; HASH_LEN]],
+        };
+        let new = BlockHashes {
+            block_size: 10,
+            hashes: vec![[1; HASH_LEN], [3; HASH_LEN], [4; HASH_LEN]],
+        };
+        // the last block is only 5 bytes long
+        assert_eq!(new.delta_size(25, &old), 15);
+        assert_eq!(old.delta_size(20, &old), 0);
+        let other_size = BlockHashes {
+            block_size: 20,
+            hashes: vec![],
+        };
+        assert_eq!(new.delta_size(25, &other_size), 25);
+    }
+
+    #[test]
+    fn update_in_place_writes_new_contents() {
+        let dir = std::env::temp_dir().join(format!("rembackup-test-{}-delta", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let (s, t) = (dir.join("s"), dir.join("t"));
+        let mut contents = vec![1; BLOCK_SIZE as usize * 2];
+        fs::write(&t, &contents).unwrap();
+        let old_hashes = BlockHashes::hash_file(&t).unwrap();
+        contents[BLOCK_SIZE as usize + 1] = 2;
+        contents.truncate(BLOCK_SIZE as usize + 100);
+        fs::write(&s, &contents).unwrap();
+        let new_hashes = update_in_place(&s, &t, &old_hashes).unwrap();
+        assert_eq!(fs::read(&t).unwrap(), contents);
+        assert_eq!(new_hashes, BlockHashes::hash_file(&s).unwrap());
+        assert_eq!(new_hashes.hashes[0], old_hashes.hashes[0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+
+use crate::{
+    args::{parse_fraction, parse_size},
+    index::{Index, IndexEntry},
+    indexchanges::IndexChange,
+    source::Source,
+    update_index::DiffError,
+};
+
+/// Protects the backup from changes which remove most of it,
+/// for example because `source` is an empty mountpoint after its disk failed to mount.
+#[derive(Clone, Args)]
+pub struct DeleteLimits {
+    /// refuse to remove more than this fraction of the entries in the index, like `0.5` or `50%`.
+    /// use `100%` to disable this check.
+    #[arg(long, value_parser = parse_fraction, default_value = "50%")]
+    pub max_delete: f64,
+    /// refuse to remove more than this much data from the backup, like `100GiB`
+    #[arg(long, value_parser = parse_size)]
+    pub max_delete_size: Option<u64>,
+    /// refuse to remove anything unless this file exists in <source>
+    /// (or, with --source, in every source from which something would be removed)
+    #[arg(long, value_name = "FILE")]
+    pub delete_marker: Option<PathBuf>,
+    /// remove entries even if that exceeds the limits above
+    #[arg(long)]
+    pub allow_mass_delete: bool,
+}
+
+/// Returns why the removals in `changes` look like a mistake, or `None` if they are within the `limits`
+pub fn check_removals(
+    source: &Source,
+    index: &Index,
+    changes: &[IndexChange],
+    limits: &DeleteLimits,
+) -> Result<Option<String>, DiffError> {
+    let removals = changes
+        .iter()
+        .filter_map(|change| match change {
+            IndexChange::RemoveFile(path) => Some((path, false)),
+            IndexChange::RemoveDir(path) => Some((path, true)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if limits.allow_mass_delete || removals.is_empty() {
+        return Ok(None);
+    }
+    if let Some(marker) = &limits.delete_marker {
+        for (name, path) in source.roots() {
+            if removals.iter().any(|(p, _)| p.starts_with(name)) && !path.join(marker).exists() {
+                return Ok(Some(format!(
+                    "{:?} doesn't exist, so {path:?} may not be the right directory.",
+                    path.join(marker)
+                )));
+            }
+        }
+    }
+    let sizes = limits.max_delete_size.is_some();
+    let (mut removed, mut removed_size) = (0, 0);
+    for (path, is_dir) in &removals {
+        let (count, size) = if *is_dir {
+            let (count, size) = count(index, path, sizes)?;
+            (count + 1, size)
+        } else {
+            (1, if sizes { file_size(index, path)? } else { 0 })
+        };
+        removed += count;
+        removed_size += size;
+    }
+    if let Some(max_delete_size) = limits.max_delete_size
+        && removed_size > max_delete_size
+    {
+        return Ok(Some(format!(
+            "{:.3} GiB would be removed from the backup, but --max-delete-size is {:.3} GiB.",
+            removed_size as f64 / (1024 * 1024 * 1024) as f64,
+            max_delete_size as f64 / (1024 * 1024 * 1024) as f64
+        )));
+    }
+    if limits.max_delete < 1.0 {
+        // only count as many entries as are needed to know that the removals are within the limit,
+        // so small removals don't walk the entire index
+        let enough = (removed as f64 / limits.max_delete).ceil() as usize;
+        let total = count_entries(index, Path::new(""), enough)?;
+        if removed as f64 > total as f64 * limits.max_delete {
+            return Ok(Some(format!(
+                "{removed} of the {total} entries in the index would be removed, but --max-delete is {}%.",
+                limits.max_delete * 100.0
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Counts the entries in the directory (but not the directory itself), but stops once there are at least `limit`
+fn count_entries(index: &Index, rel_path: &Path, limit: usize) -> Result<usize, DiffError> {
+    let entries = index
+        .read_dir(rel_path)
+        .map_err(|e| {
+            (
+                "reading directory in index".to_owned(),
+                index.path().join(rel_path),
+                e,
+            )
+        })?
+        .unwrap_or_default();
+    let mut total = entries.len();
+    for (name, is_dir) in entries {
+        if total >= limit {
+            break;
+        }
+        if is_dir {
+            total += count_entries(index, &rel_path.join(name), limit - total)?;
+        }
+    }
+    Ok(total)
+}
+
+/// Counts the entries in the directory (but not the directory itself),
+/// and adds up the size of its files if `sizes` is true.
+fn count(index: &Index, rel_path: &Path, sizes: bool) -> Result<(usize, u64), DiffError> {
+    let entries = index
+        .read_dir(rel_path)
+        .map_err(|e| {
+            (
+                "reading directory in index".to_owned(),
+                index.path().join(rel_path),
+                e,
+            )
+        })?
+        .unwrap_or_default();
+    let (mut total, mut total_size) = (entries.len(), 0);
+    for (name, is_dir) in entries {
+        let rel_path = rel_path.join(name);
+        let (count, size) = if is_dir {
+            count(index, &rel_path, sizes)?
+        } else if sizes {
+            (0, file_size(index, &rel_path)?)
+        } else {
+            (0, 0)
+        };
+        total += count;
+        total_size += size;
+    }
+    Ok((total, total_size))
+}
+
+fn file_size(index: &Index, rel_path: &Path) -> Result<u64, DiffError> {
+    match index.get(rel_path) {
+        Ok(Some(IndexEntry::File(index_file))) => Ok(index_file.size),
+        Ok(_) => Ok(0),
+        Err(e) => Err((
+            "reading file in index".to_owned(),
+            index.path().join(rel_path),
+            e,
+        )),
+    }
+}
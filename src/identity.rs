@@ -0,0 +1,73 @@
+use std::{
+    fs, io,
+    path::Path,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::index::Index;
+
+/// Contains the id of the index which the target belongs to
+const TARGET_ID_FILE: &str = ".rembackup_target";
+
+/// Checks that `target` contains the backup described by `index`.
+/// Returns `Ok(Err(_))` with an explanation if it doesn't.
+pub fn check_target(index: &Index, target: &Path) -> io::Result<Result<(), String>> {
+    let target_id = read_target_id(target)?;
+    Ok(match (index.id(), target_id.as_deref()) {
+        (Some(index_id), Some(target_id)) if index_id == target_id => Ok(()),
+        // neither was used before, or they were used before identity markers existed
+        (None, None) => Ok(()),
+        (Some(_), Some(_)) => Err(format!(
+            "{target:?} contains a backup made with another index than {:?}.",
+            index.path()
+        )),
+        (Some(_), None) => Err(format!(
+            "{target:?} doesn't contain the backup made with {:?}.",
+            index.path()
+        )),
+        (None, Some(_)) => Err(format!(
+            "{target:?} contains a backup made with another index, but {:?} is new.",
+            index.path()
+        )),
+    })
+}
+
+/// Marks `target` as belonging to `index`, giving the index an id first if it doesn't have one yet.
+/// Should only be used after `check_target`, or if the target is adopted intentionally.
+pub fn mark_target(index: &mut Index, target: &Path) -> io::Result<()> {
+    let id = match index.id() {
+        Some(id) => id.to_owned(),
+        None => {
+            let id = new_id(index.path());
+            index.set_id(id.clone());
+            index.save()?;
+            id
+        }
+    };
+    if read_target_id(target)?.as_deref() != Some(id.as_str()) {
+        fs::create_dir_all(target)?;
+        fs::write(target.join(TARGET_ID_FILE), format!("{id}\n"))?;
+    }
+    Ok(())
+}
+
+fn read_target_id(target: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(target.join(TARGET_ID_FILE)) {
+        Ok(id) => Ok(Some(id.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// An id which is very unlikely to be used by any other index
+fn new_id(index_path: &Path) -> String {
+    let mut hasher = blake3::Hasher::new();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(&now.as_nanos().to_le_bytes());
+    hasher.update(&process::id().to_le_bytes());
+    hasher.update(index_path.as_os_str().as_encoded_bytes());
+    hasher.finalize().to_hex()[..32].to_owned()
+}
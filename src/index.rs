@@ -0,0 +1,1014 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufWriter, Read, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{indexfile::IndexFile, repr_file::ReprFile};
+
+/// The version of the index format written by this version of rembackup.
+/// Indexes with a higher version are never modified.
+///
+/// 1. no version information
+/// 2. adds version information (`.rembackup_index` file or packed header)
+/// 3. adds permissions, owner and group of files and directories, and modification times of directories
+/// 4. adds nanoseconds to modification times
+/// 5. adds special files (fifos, sockets and device nodes), which were copied like regular files before
+/// 6. adds inode numbers, which are used to detect moved files and directories
+/// 7. adds hashes of the blocks of large files, which are used to only write the blocks which changed
+pub const INDEX_VERSION: u32 = 7;
+
+/// Names which start with this are reserved for rembackup itself and will not be backed up.
+pub const RESERVED_PREFIX: &str = ".rembackup_";
+/// Contains the meta information of a tree index
+const TREE_META_FILE: &str = ".rembackup_index";
+/// Contains the `IndexFile` describing a directory in a tree index
+const TREE_DIR_META_FILE: &str = ".rembackup_dir";
+/// Lists the files which are being copied, see `Index::pending_path`
+const TREE_PENDING_FILE: &str = ".rembackup_pending";
+
+/// The packed index is rewritten at most this often while changes are being applied,
+/// so an interrupted backup doesn't lose all of its progress.
+const PACKED_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Packed index files start with these bytes, followed by the version as a little-endian u32.
+/// Since version 2, this is followed by the meta information (u32 length + bytes).
+/// Since version 3, directories (including the root directory, which follows the meta information)
+/// have an `IndexFile` (u32 length + bytes, empty if unknown) before their entries.
+const PACKED_MAGIC: &[u8; 16] = b"rembackup-index\n";
+
+const PACKED_END_OF_DIR: u8 = 0;
+const PACKED_DIR: u8 = 1;
+const PACKED_FILE: u8 = 2;
+const PACKED_SYMLINK: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum IndexFormat {
+    /// a directory tree mirroring `source`, with one small text file per file
+    Tree,
+    /// a single file containing the entire index
+    Packed,
+}
+
+#[derive(Debug)]
+pub enum IndexEntry {
+    Dir,
+    File(IndexFile),
+    Symlink(PathBuf),
+}
+
+/// The index, which stores information about all files in the last backup.
+/// All paths used with an index are relative to `source`.
+pub struct Index {
+    path: PathBuf,
+    /// Information about the index itself, like the version of its format
+    meta: HashMap<String, String>,
+    /// if true, `meta` has changed and must be saved
+    meta_dirty: bool,
+    /// if true, the generation has already been increased for the changes made by this process
+    generation_increased: bool,
+    storage: Storage,
+}
+
+enum Storage {
+    /// The index is a directory with the same structure as `source`.
+    /// Files are stored as text files (see `IndexFile`), symlinks are stored as symlinks.
+    Tree,
+    /// The index is a single file, which is loaded into memory entirely.
+    Packed {
+        root: PackedDir,
+        /// if true, there are changes which haven't been saved yet
+        dirty: bool,
+        last_save: Instant,
+    },
+}
+
+#[derive(Default)]
+struct PackedDir {
+    /// permissions, owner and modification time of the directory itself
+    meta: Option<IndexFile>,
+    entries: BTreeMap<OsString, PackedEntry>,
+}
+enum PackedEntry {
+    Dir(PackedDir),
+    File(IndexFile),
+    Symlink(PathBuf),
+}
+
+impl Index {
+    /// Opens the index at `path`, or prepares a new one if nothing exists there yet.
+    /// If `format` is set and the existing index uses a different format, it is converted.
+    /// Indexes using an older version of the format are migrated to the current version.
+    pub fn open(path: &Path, format: Option<IndexFormat>) -> io::Result<Self> {
+        let existing = match fs::metadata(path) {
+            Ok(meta) if meta.is_dir() => Some(IndexFormat::Tree),
+            Ok(_) => Some(IndexFormat::Packed),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(match (existing, format) {
+            (None, format) => Self::new(path, format.unwrap_or(IndexFormat::Tree)),
+            (Some(existing), format) => {
+                let mut index = if format.is_some_and(|f| f != existing) {
+                    Self::convert(path, existing, format.unwrap())?
+                } else {
+                    Self::load(path, existing)?
+                };
+                index.migrate()?;
+                index
+            }
+        })
+    }
+    fn new(path: &Path, format: IndexFormat) -> Self {
+        Self {
+            path: path.to_owned(),
+            meta: [("Version".to_owned(), INDEX_VERSION.to_string())].into(),
+            meta_dirty: true,
+            generation_increased: false,
+            storage: match format {
+                IndexFormat::Tree => Storage::Tree,
+                IndexFormat::Packed => Storage::Packed {
+                    root: PackedDir::default(),
+                    dirty: true,
+                    last_save: Instant::now(),
+                },
+            },
+        }
+    }
+    fn load(path: &Path, format: IndexFormat) -> io::Result<Self> {
+        let (meta, storage) = match format {
+            IndexFormat::Tree => {
+                let meta = match fs::read_to_string(path.join(TREE_META_FILE)) {
+                    Ok(text) => HashMap::load(&text).map_err(invalid_data)?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                    Err(e) => return Err(e),
+                };
+                (meta, Storage::Tree)
+            }
+            IndexFormat::Packed => {
+                let (meta, root) = load_packed(path)?;
+                let storage = Storage::Packed {
+                    root,
+                    dirty: false,
+                    last_save: Instant::now(),
+                };
+                (meta, storage)
+            }
+        };
+        let index = Self {
+            path: path.to_owned(),
+            meta,
+            meta_dirty: false,
+            generation_increased: false,
+            storage,
+        };
+        // check this before doing anything else with the index
+        index.version()?;
+        Ok(index)
+    }
+    /// Writes the index at `path` in the new format, then replaces the old index,
+    /// which is kept at `<path>.old` so it can be deleted manually.
+    fn convert(path: &Path, from: IndexFormat, to: IndexFormat) -> io::Result<Self> {
+        let converting = path_with_suffix(path, ".converting");
+        let old = path_with_suffix(path, ".old");
+        if fs::symlink_metadata(&old).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("can't convert index, because {old:?} already exists. Remove it first."),
+            ));
+        }
+        let Self { meta, storage, .. } = Self::load(path, from)?;
+        eprintln!("[info] converting index from {from:?} to {to:?} format...");
+        let root = match storage {
+            Storage::Tree => PackedDir::load_tree(path)?,
+            Storage::Packed { root, .. } => root,
+        };
+        match to {
+            IndexFormat::Tree => {
+                let _ = fs::remove_dir_all(&converting);
+                fs::create_dir(&converting)?;
+                fs::write(converting.join(TREE_META_FILE), meta.save())?;
+                root.save_tree(&converting)?;
+            }
+            IndexFormat::Packed => write_packed(&converting, &meta, &root)?,
+        }
+        fs::rename(path, &old)?;
+        fs::rename(&converting, path)?;
+        eprintln!("[info] converted index, the old index was moved to {old:?}.");
+        Ok(Self {
+            path: path.to_owned(),
+            meta,
+            meta_dirty: false,
+            generation_increased: false,
+            storage: match to {
+                IndexFormat::Tree => Storage::Tree,
+                IndexFormat::Packed => Storage::Packed {
+                    root,
+                    dirty: false,
+                    last_save: Instant::now(),
+                },
+            },
+        })
+    }
+    /// Returns an error if the index was written by a newer version of rembackup
+    fn version(&self) -> io::Result<u32> {
+        let version = match self.meta.get("Version") {
+            Some(v) => v
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid index version {v:?}")))?,
+            None => 1,
+        };
+        if version > INDEX_VERSION {
+            return Err(unsupported_version(version));
+        }
+        Ok(version)
+    }
+    /// Brings an index using an older version of the format up to date.
+    /// Fields which were added in newer versions are simply unknown in older index files,
+    /// so migrating never requires files to be copied again.
+    fn migrate(&mut self) -> io::Result<()> {
+        let version = self.version()?;
+        if version == INDEX_VERSION {
+            return Ok(());
+        }
+        eprintln!("[info] migrating index from version {version} to version {INDEX_VERSION}...");
+        for version in version..INDEX_VERSION {
+            match version {
+                // version 2 only added the version information, which is written below
+                1 => {}
+                // missing permissions and directory timestamps will be updated on the target
+                // during the next backup, without copying any files
+                2 => {}
+                // timestamps without nanoseconds are compared with full seconds only
+                3 => {}
+                // special files which were copied as regular files will be recreated on the target
+                4 => {}
+                // inode numbers are added during the next backup, without copying any files
+                5 => {}
+                // block hashes are added when large files are copied again
+                6 => {}
+                _ => unreachable!("no migration from index version {version}"),
+            }
+        }
+        self.meta
+            .insert("Version".to_owned(), INDEX_VERSION.to_string());
+        self.meta_dirty = true;
+        self.save()
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Increases with every backup (or other command) which changes the index,
+    /// so a plan can detect that the index it was made for has changed.
+    pub fn generation(&self) -> u64 {
+        self.meta
+            .get("Generation")
+            .and_then(|g| g.parse().ok())
+            .unwrap_or(0)
+    }
+    /// Increases the generation before the first change since the last `save` is made.
+    /// Tree indexes are changed on disk immediately, so the new generation is written right away.
+    fn changing(&mut self) -> io::Result<()> {
+        if !self.generation_increased {
+            self.generation_increased = true;
+            self.meta
+                .insert("Generation".to_owned(), (self.generation() + 1).to_string());
+            self.meta_dirty = true;
+            if let Storage::Tree = self.storage {
+                self.write()?;
+            }
+        }
+        Ok(())
+    }
+    /// Where the files which are being copied to the target are listed while changes are applied.
+    /// For tree indexes, this is inside the index, for packed indexes next to it.
+    pub fn pending_path(&self) -> PathBuf {
+        match self.storage {
+            Storage::Tree => self.path.join(TREE_PENDING_FILE),
+            Storage::Packed { .. } => path_with_suffix(&self.path, ".pending"),
+        }
+    }
+    /// Files which are created next to the index while it is saved or converted, or changes are applied
+    pub fn sidecar_paths(&self) -> Vec<PathBuf> {
+        [".tmp", ".old", ".converting", ".pending"]
+            .into_iter()
+            .map(|suffix| path_with_suffix(&self.path, suffix))
+            .collect()
+    }
+    /// Identifies the index, so its target can't be mixed up with another index's target.
+    /// `None` until the index is used with a target for the first time.
+    pub fn id(&self) -> Option<&str> {
+        self.meta.get("Id").map(|id| id.as_str())
+    }
+    /// Sets the id. Like other meta information, it is written by the next `save`.
+    pub fn set_id(&mut self, id: String) {
+        self.meta.insert("Id".to_owned(), id);
+        self.meta_dirty = true;
+    }
+    pub fn format(&self) -> IndexFormat {
+        match self.storage {
+            Storage::Tree => IndexFormat::Tree,
+            Storage::Packed { .. } => IndexFormat::Packed,
+        }
+    }
+
+    /// Returns the names of all entries in the directory and whether they are directories,
+    /// or `None` if the directory isn't in the index.
+    pub fn read_dir(&self, rel_path: &Path) -> io::Result<Option<HashMap<OsString, bool>>> {
+        match &self.storage {
+            Storage::Tree => match fs::read_dir(self.path.join(rel_path)) {
+                Err(_) => Ok(None),
+                Ok(e) => Ok(Some(
+                    e.into_iter()
+                        .filter_map(|v| v.ok())
+                        .filter(|v| !is_reserved(Path::new(&v.file_name())))
+                        .map(|v| Ok((v.file_name(), v.file_type()?.is_dir())))
+                        .collect::<io::Result<_>>()?,
+                )),
+            },
+            Storage::Packed { root, .. } => Ok(root.dir(rel_path).map(|dir| {
+                dir.entries
+                    .iter()
+                    .map(|(name, entry)| (name.clone(), matches!(entry, PackedEntry::Dir(_))))
+                    .collect()
+            })),
+        }
+    }
+    /// Returns the entry at this path, or `None` if it isn't in the index.
+    pub fn get(&self, rel_path: &Path) -> io::Result<Option<IndexEntry>> {
+        match &self.storage {
+            Storage::Tree => {
+                let path = self.path.join(rel_path);
+                let meta = match path.symlink_metadata() {
+                    Ok(meta) => meta,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                Ok(Some(if meta.is_symlink() {
+                    IndexEntry::Symlink(fs::read_link(&path)?)
+                } else if meta.is_dir() {
+                    IndexEntry::Dir
+                } else {
+                    IndexEntry::File(IndexFile::from_path(&path)?.map_err(invalid_data)?)
+                }))
+            }
+            Storage::Packed { root, .. } => {
+                let (parent, name) = split_path(rel_path)?;
+                Ok(root
+                    .dir(parent)
+                    .and_then(|dir| dir.entries.get(name))
+                    .map(|entry| match entry {
+                        PackedEntry::Dir(_) => IndexEntry::Dir,
+                        PackedEntry::File(f) => IndexEntry::File(f.clone()),
+                        PackedEntry::Symlink(link) => IndexEntry::Symlink(link.clone()),
+                    }))
+            }
+        }
+    }
+
+    /// Returns the permissions, owner and modification time of a directory, if they are known.
+    pub fn dir_metadata(&self, rel_path: &Path) -> io::Result<Option<IndexFile>> {
+        match &self.storage {
+            Storage::Tree => {
+                match IndexFile::from_path(&self.path.join(rel_path).join(TREE_DIR_META_FILE)) {
+                    Ok(f) => Ok(Some(f.map_err(invalid_data)?)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            Storage::Packed { root, .. } => Ok(root.dir(rel_path).and_then(|dir| dir.meta.clone())),
+        }
+    }
+    /// Sets the permissions, owner and modification time of a directory
+    pub fn write_dir_metadata(&mut self, rel_path: &Path, meta: &IndexFile) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => fs::write(
+                self.path.join(rel_path).join(TREE_DIR_META_FILE),
+                meta.save(),
+            ),
+            Storage::Packed { root, dirty, .. } => {
+                root.dir_mut(rel_path)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in index"))?
+                    .meta = Some(meta.clone());
+                *dirty = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Like `fs::create_dir_all`
+    pub fn create_dir(&mut self, rel_path: &Path) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => fs::create_dir_all(self.path.join(rel_path)),
+            Storage::Packed { root, dirty, .. } => {
+                let mut dir = root;
+                for name in normal_components(rel_path)? {
+                    let entry = dir
+                        .entries
+                        .entry(name.to_owned())
+                        .or_insert_with(|| PackedEntry::Dir(PackedDir::default()));
+                    dir = match entry {
+                        PackedEntry::Dir(dir) => dir,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::AlreadyExists,
+                                "a file with this name already exists",
+                            ));
+                        }
+                    };
+                }
+                *dirty = true;
+                Ok(())
+            }
+        }
+    }
+    /// Adds or replaces a file. Its parent directory must already exist.
+    pub fn write_file(&mut self, rel_path: &Path, file: &IndexFile) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => fs::write(self.path.join(rel_path), file.save()),
+            Storage::Packed { root, dirty, .. } => {
+                root.insert(rel_path, PackedEntry::File(file.clone()))?;
+                *dirty = true;
+                Ok(())
+            }
+        }
+    }
+    /// Adds or replaces a symlink. Its parent directory must already exist.
+    pub fn write_symlink(&mut self, rel_path: &Path, link_target: &Path) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => {
+                let path = self.path.join(rel_path);
+                let _ = fs::remove_file(&path);
+                std::os::unix::fs::symlink(link_target, &path)
+            }
+            Storage::Packed { root, dirty, .. } => {
+                root.insert(rel_path, PackedEntry::Symlink(link_target.to_owned()))?;
+                *dirty = true;
+                Ok(())
+            }
+        }
+    }
+    /// Moves a file, symlink or directory. The new parent directory must already exist.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => fs::rename(self.path.join(from), self.path.join(to)),
+            Storage::Packed { root, dirty, .. } => {
+                // check this first, so the entry isn't lost if it can't be inserted
+                let (parent, name) = split_path(to)?;
+                match root.dir(parent).map(|dir| dir.entries.get(name)) {
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "parent directory not in index",
+                        ));
+                    }
+                    Some(Some(PackedEntry::Dir(_))) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "a directory with this name already exists",
+                        ));
+                    }
+                    Some(_) => {}
+                }
+                let entry = root.take(from)?;
+                *dirty = true;
+                root.insert(to, entry)
+            }
+        }
+    }
+    /// Removes a file or symlink
+    pub fn remove_file(&mut self, rel_path: &Path) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => fs::remove_file(self.path.join(rel_path)),
+            Storage::Packed { root, dirty, .. } => {
+                root.remove(rel_path, false)?;
+                *dirty = true;
+                Ok(())
+            }
+        }
+    }
+    /// Removes a directory and everything in it
+    pub fn remove_dir(&mut self, rel_path: &Path) -> io::Result<()> {
+        self.changing()?;
+        match &mut self.storage {
+            Storage::Tree => fs::remove_dir_all(self.path.join(rel_path)),
+            Storage::Packed { root, dirty, .. } => {
+                root.remove(rel_path, true)?;
+                *dirty = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Saves all changes which haven't been saved yet.
+    /// Changes made after this increase the generation again,
+    /// so each batch of changes (like one backup in `rembackup watch`) gets its own generation.
+    pub fn save(&mut self) -> io::Result<()> {
+        self.write()?;
+        self.generation_increased = false;
+        Ok(())
+    }
+    /// Writes all changes which haven't been written yet,
+    /// and records which version of rembackup last wrote to the index.
+    fn write(&mut self) -> io::Result<()> {
+        if self.meta.get("Writer").map(|v| v.as_str()) != Some(WRITER) {
+            self.meta.insert("Writer".to_owned(), WRITER.to_owned());
+            self.meta_dirty = true;
+        }
+        match &mut self.storage {
+            Storage::Tree => {
+                if self.meta_dirty {
+                    fs::create_dir_all(&self.path)?;
+                    fs::write(self.path.join(TREE_META_FILE), self.meta.save())?;
+                }
+            }
+            Storage::Packed {
+                root,
+                dirty,
+                last_save,
+            } => {
+                if *dirty || self.meta_dirty {
+                    write_packed(&self.path, &self.meta, root)?;
+                    *dirty = false;
+                }
+                *last_save = Instant::now();
+            }
+        }
+        self.meta_dirty = false;
+        Ok(())
+    }
+    /// Like `save`, but only writes the index if the last save was a while ago.
+    /// Doesn't end the current batch of changes, so the generation isn't increased again.
+    pub fn save_periodically(&mut self) -> io::Result<()> {
+        match &self.storage {
+            Storage::Packed { last_save, .. } if last_save.elapsed() >= PACKED_SAVE_INTERVAL => {
+                self.write()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+const WRITER: &str = concat!("rembackup ", env!("CARGO_PKG_VERSION"));
+
+/// True if the path's file name starts with the `RESERVED_PREFIX`
+pub fn is_reserved(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_bytes().starts_with(RESERVED_PREFIX.as_bytes()))
+}
+
+fn load_packed(path: &Path) -> io::Result<(HashMap<String, String>, PackedDir)> {
+    let mut bytes = vec![];
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    let mut reader = PackedReader(&bytes, 0);
+    if reader.take(PACKED_MAGIC.len())? != PACKED_MAGIC {
+        return Err(invalid_data(format!("{path:?} is not a rembackup index")));
+    }
+    let version = reader.u32()?;
+    if version > INDEX_VERSION {
+        return Err(unsupported_version(version));
+    }
+    reader.1 = version;
+    let mut meta = if version >= 2 {
+        HashMap::load(&String::from_utf8_lossy(reader.bytes()?)).map_err(invalid_data)?
+    } else {
+        HashMap::new()
+    };
+    // the header is what older versions of rembackup check, so it takes precedence
+    if version >= 2 {
+        meta.insert("Version".to_owned(), version.to_string());
+    }
+    let root_meta = reader.dir_meta()?;
+    let mut root = reader.dir()?;
+    root.meta = root_meta;
+    Ok((meta, root))
+}
+/// Writes the index to a temporary file first, so the old index remains intact if this fails.
+fn write_packed(path: &Path, meta: &HashMap<String, String>, root: &PackedDir) -> io::Result<()> {
+    let tmp = path_with_suffix(path, ".tmp");
+    let mut file = BufWriter::new(fs::File::create(&tmp)?);
+    file.write_all(PACKED_MAGIC)?;
+    file.write_all(&INDEX_VERSION.to_le_bytes())?;
+    write_bytes(&mut file, meta.save().as_bytes())?;
+    root.write_packed_meta(&mut file)?;
+    root.write_packed(&mut file)?;
+    file.into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+impl PackedDir {
+    fn insert(&mut self, rel_path: &Path, entry: PackedEntry) -> io::Result<()> {
+        let (parent, name) = split_path(rel_path)?;
+        let dir = self.dir_mut(parent).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "parent directory not in index")
+        })?;
+        if let Some(PackedEntry::Dir(_)) = dir.entries.get(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                "a directory with this name already exists",
+            ));
+        }
+        dir.entries.insert(name.to_owned(), entry);
+        Ok(())
+    }
+    fn remove(&mut self, rel_path: &Path, is_dir: bool) -> io::Result<()> {
+        let (parent, name) = split_path(rel_path)?;
+        let dir = self.dir_mut(parent);
+        match dir.as_ref().and_then(|dir| dir.entries.get(name)) {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "not in index")),
+            Some(PackedEntry::Dir(_)) if !is_dir => Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                "expected a file, but found a directory",
+            )),
+            Some(PackedEntry::File(_) | PackedEntry::Symlink(_)) if is_dir => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "expected a directory, but found a file",
+            )),
+            Some(_) => {
+                dir.expect("dir was used to get the entry").entries.remove(name);
+                Ok(())
+            }
+        }
+    }
+    /// Removes an entry of any type and returns it
+    fn take(&mut self, rel_path: &Path) -> io::Result<PackedEntry> {
+        let (parent, name) = split_path(rel_path)?;
+        self.dir_mut(parent)
+            .and_then(|dir| dir.entries.remove(name))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in index"))
+    }
+    fn dir(&self, rel_path: &Path) -> Option<&Self> {
+        let mut dir = self;
+        for name in normal_components(rel_path).ok()? {
+            dir = match dir.entries.get(name)? {
+                PackedEntry::Dir(dir) => dir,
+                _ => return None,
+            };
+        }
+        Some(dir)
+    }
+    fn dir_mut(&mut self, rel_path: &Path) -> Option<&mut Self> {
+        let mut dir = self;
+        for name in normal_components(rel_path).ok()? {
+            dir = match dir.entries.get_mut(name)? {
+                PackedEntry::Dir(dir) => dir,
+                _ => return None,
+            };
+        }
+        Some(dir)
+    }
+    fn load_tree(path: &Path) -> io::Result<Self> {
+        let mut dir = Self::default();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_name() == TREE_DIR_META_FILE {
+                dir.meta = IndexFile::from_path(&entry.path())?.ok();
+            }
+            if is_reserved(Path::new(&entry.file_name())) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            let packed_entry = if file_type.is_symlink() {
+                PackedEntry::Symlink(fs::read_link(entry.path())?)
+            } else if file_type.is_dir() {
+                PackedEntry::Dir(Self::load_tree(&entry.path())?)
+            } else {
+                match IndexFile::from_path(&entry.path())? {
+                    Ok(f) => PackedEntry::File(f),
+                    Err(e) => {
+                        // this file will be copied again on the next backup
+                        eprintln!("[warn] skipping invalid index file {:?}: {e}", entry.path());
+                        continue;
+                    }
+                }
+            };
+            dir.entries.insert(entry.file_name(), packed_entry);
+        }
+        Ok(dir)
+    }
+    fn save_tree(&self, path: &Path) -> io::Result<()> {
+        if let Some(meta) = &self.meta {
+            fs::write(path.join(TREE_DIR_META_FILE), meta.save())?;
+        }
+        for (name, entry) in &self.entries {
+            let path = path.join(name);
+            match entry {
+                PackedEntry::Dir(dir) => {
+                    fs::create_dir(&path)?;
+                    dir.save_tree(&path)?;
+                }
+                PackedEntry::File(f) => fs::write(&path, f.save())?,
+                PackedEntry::Symlink(link) => std::os::unix::fs::symlink(link, &path)?,
+            }
+        }
+        Ok(())
+    }
+    fn write_packed_meta(&self, w: &mut impl Write) -> io::Result<()> {
+        let meta = self.meta.as_ref().map(|m| m.save()).unwrap_or_default();
+        write_bytes(w, meta.as_bytes())
+    }
+    fn write_packed(&self, w: &mut impl Write) -> io::Result<()> {
+        for (name, entry) in &self.entries {
+            match entry {
+                PackedEntry::Dir(dir) => {
+                    w.write_all(&[PACKED_DIR])?;
+                    write_bytes(w, name.as_bytes())?;
+                    dir.write_packed_meta(w)?;
+                    dir.write_packed(w)?;
+                }
+                PackedEntry::File(f) => {
+                    w.write_all(&[PACKED_FILE])?;
+                    write_bytes(w, name.as_bytes())?;
+                    write_bytes(w, f.save().as_bytes())?;
+                }
+                PackedEntry::Symlink(link) => {
+                    w.write_all(&[PACKED_SYMLINK])?;
+                    write_bytes(w, name.as_bytes())?;
+                    write_bytes(w, link.as_os_str().as_bytes())?;
+                }
+            }
+        }
+        w.write_all(&[PACKED_END_OF_DIR])
+    }
+}
+
+/// Reads the packed format, which is a list of entries for each directory.
+/// Each entry starts with a type byte and a name (u32 length + bytes).
+/// Directories are followed by their entries, files by their `IndexFile`
+/// and symlinks by their target (both u32 length + bytes).
+/// The end of a directory is marked by a `PACKED_END_OF_DIR` byte.
+/// The second field is the version of the packed index.
+struct PackedReader<'a>(&'a [u8], u32);
+impl<'a> PackedReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("unexpected end of packed index".to_owned()));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("took 4 bytes"),
+        ))
+    }
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    fn dir_meta(&mut self) -> io::Result<Option<IndexFile>> {
+        if self.1 < 3 {
+            return Ok(None);
+        }
+        match self.bytes()? {
+            [] => Ok(None),
+            meta => Ok(Some(
+                IndexFile::load(&String::from_utf8_lossy(meta)).map_err(invalid_data)?,
+            )),
+        }
+    }
+    fn dir(&mut self) -> io::Result<PackedDir> {
+        let mut dir = PackedDir::default();
+        loop {
+            let entry_type = self.take(1)?[0];
+            if entry_type == PACKED_END_OF_DIR {
+                return Ok(dir);
+            }
+            let name = OsString::from_vec(self.bytes()?.to_vec());
+            let entry = match entry_type {
+                PACKED_DIR => {
+                    let meta = self.dir_meta()?;
+                    let mut dir = self.dir()?;
+                    dir.meta = meta;
+                    PackedEntry::Dir(dir)
+                }
+                PACKED_FILE => PackedEntry::File(
+                    IndexFile::load(&String::from_utf8_lossy(self.bytes()?))
+                        .map_err(invalid_data)?,
+                ),
+                PACKED_SYMLINK => {
+                    PackedEntry::Symlink(OsString::from_vec(self.bytes()?.to_vec()).into())
+                }
+                _ => {
+                    return Err(invalid_data(format!(
+                        "unknown entry type {entry_type} in packed index"
+                    )));
+                }
+            };
+            dir.entries.insert(name, entry);
+        }
+    }
+}
+
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry too long"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytes)
+}
+
+fn unsupported_version(version: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "the index uses version {version} of the index format, but this version of rembackup only supports up to version {INDEX_VERSION}. Please update rembackup."
+        ),
+    )
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn normal_components(rel_path: &Path) -> io::Result<Vec<&OsStr>> {
+    rel_path
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => Ok(name),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{rel_path:?} is not a relative path"),
+            )),
+        })
+        .collect()
+}
+
+fn split_path(rel_path: &Path) -> io::Result<(&Path, &OsStr)> {
+    match (rel_path.parent(), rel_path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{rel_path:?} has no file name"),
+        )),
+    }
+}
+
+pub fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::BlockHashes;
+
+    /// A path in the temporary directory which doesn't exist yet
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rembackup-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn index_file(size: u64) -> IndexFile {
+        IndexFile {
+            size,
+            last_modified: Some(1_700_000_000),
+            last_modified_nanos: Some(5),
+            hash: Some("abc".to_owned()),
+            mode: Some(0o640),
+            uid: Some(0),
+            gid: Some(100),
+            special: None,
+            inode: Some(7),
+            blocks: Some(BlockHashes {
+                block_size: 4096,
+                hashes: vec![[1; 8], [2; 8]],
+            }),
+        }
+    }
+
+    fn fill(index: &mut Index) {
+        index.create_dir(Path::new("a/b")).unwrap();
+        index
+            .write_file(Path::new("a/b/file"), &index_file(8000))
+            .unwrap();
+        index
+            .write_file(
+                Path::new(OsStr::from_bytes(b"a/not utf-8 \xff")),
+                &index_file(1),
+            )
+            .unwrap();
+        index
+            .write_symlink(Path::new("a/link"), Path::new("b/file"))
+            .unwrap();
+        index
+            .write_dir_metadata(Path::new("a"), &index_file(0))
+            .unwrap();
+        index.set_id("test-id".to_owned());
+        index.save().unwrap();
+    }
+
+    fn check(index: &Index) {
+        assert_eq!(index.id(), Some("test-id"));
+        assert_eq!(index.version().unwrap(), INDEX_VERSION);
+        let a = index.read_dir(Path::new("a")).unwrap().unwrap();
+        assert_eq!(a.len(), 3);
+        assert_eq!(a.get(OsStr::new("b")), Some(&true));
+        assert_eq!(a.get(OsStr::new("link")), Some(&false));
+        assert_eq!(a.get(OsStr::from_bytes(b"not utf-8 \xff")), Some(&false));
+        match index.get(Path::new("a/b/file")).unwrap() {
+            Some(IndexEntry::File(file)) => assert_eq!(file.save(), index_file(8000).save()),
+            other => panic!("expected a file, found {other:?}"),
+        }
+        assert!(matches!(
+            index.get(Path::new("a/link")).unwrap(),
+            Some(IndexEntry::Symlink(link)) if link == Path::new("b/file")
+        ));
+        assert!(matches!(
+            index.get(Path::new("a/b")).unwrap(),
+            Some(IndexEntry::Dir)
+        ));
+        assert!(index.get(Path::new("a/missing")).unwrap().is_none());
+        assert_eq!(
+            index
+                .dir_metadata(Path::new("a"))
+                .unwrap()
+                .map(|m| m.save()),
+            Some(index_file(0).save())
+        );
+    }
+
+    #[test]
+    fn packed_round_trip() {
+        let path = temp_path("packed");
+        let mut index = Index::open(&path, Some(IndexFormat::Packed)).unwrap();
+        fill(&mut index);
+        assert!(path.is_file());
+        assert!(!path_with_suffix(&path, ".tmp").exists());
+        let index = Index::open(&path, None).unwrap();
+        assert_eq!(index.format(), IndexFormat::Packed);
+        check(&index);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn convert_packed_to_tree_and_back() {
+        let path = temp_path("convert");
+        let old = path_with_suffix(&path, ".old");
+        fill(&mut Index::open(&path, Some(IndexFormat::Packed)).unwrap());
+        let index = Index::open(&path, Some(IndexFormat::Tree)).unwrap();
+        assert_eq!(index.format(), IndexFormat::Tree);
+        check(&index);
+        assert!(old.is_file());
+        // the old index must be removed before converting again
+        assert!(Index::open(&path, Some(IndexFormat::Packed)).is_err());
+        fs::remove_file(&old).unwrap();
+        let index = Index::open(&path, Some(IndexFormat::Packed)).unwrap();
+        assert_eq!(index.format(), IndexFormat::Packed);
+        check(&index);
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&old).unwrap();
+    }
+
+    #[test]
+    fn generation_increases_with_each_save() {
+        for format in [IndexFormat::Tree, IndexFormat::Packed] {
+            let path = temp_path(&format!("generation-{format:?}"));
+            let mut index = Index::open(&path, Some(format)).unwrap();
+            assert_eq!(index.generation(), 0);
+            index.create_dir(Path::new("a")).unwrap();
+            index.create_dir(Path::new("b")).unwrap();
+            index.save().unwrap();
+            assert_eq!(index.generation(), 1);
+            index.create_dir(Path::new("c")).unwrap();
+            index.save_periodically().unwrap();
+            index.create_dir(Path::new("d")).unwrap();
+            index.save().unwrap();
+            assert_eq!(index.generation(), 2);
+            // saving without changes doesn't increase it
+            index.save().unwrap();
+            assert_eq!(Index::open(&path, None).unwrap().generation(), 2);
+            let _ = fs::remove_dir_all(&path);
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn packed_rejects_newer_version_and_garbage() {
+        let path = temp_path("newer");
+        let mut bytes = PACKED_MAGIC.to_vec();
+        bytes.extend_from_slice(&(INDEX_VERSION + 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert!(Index::open(&path, None).is_err());
+        fs::write(&path, "not an index").unwrap();
+        assert!(Index::open(&path, None).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
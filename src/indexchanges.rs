@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::indexfile::IndexFile;
 
@@ -8,10 +8,53 @@ pub enum IndexChange {
     AddDir(PathBuf, bool, u64),
     /// Add or update a file
     AddFile(PathBuf, IndexFile),
+    /// Update a file on the target by only writing the blocks which changed.
+    /// The 2nd `IndexFile` is the old one, including its `blocks`, and the number is roughly how many bytes will be written.
+    UpdateFile(PathBuf, IndexFile, IndexFile, u64),
     /// Same as `AddFile`, just that it creates a symlink pointing to the 2nd path
     AddSymlink(PathBuf, PathBuf),
+    /// Same as `AddFile`, but creates a fifo, socket or device node (`IndexFile::special`) instead of copying
+    AddSpecial(PathBuf, IndexFile),
+    /// Move a file, symlink or directory from the 1st path to the 2nd path instead of copying it again.
+    /// Moves are applied before all other changes, and the 2nd path's parent directories are created if needed.
+    Move(PathBuf, PathBuf),
     /// Remove a file or symlink
     RemoveFile(PathBuf),
     /// Remove a directory (recursively)
     RemoveDir(PathBuf),
+    /// Update permissions, owner and modification time without copying any contents.
+    /// The bool is true for directories, which are updated after all of their contents.
+    UpdateMetadata(PathBuf, bool, IndexFile),
+}
+
+impl IndexChange {
+    /// The paths (relative to the index and target) which this change affects
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::AddDir(path, _, _)
+            | Self::AddFile(path, _)
+            | Self::UpdateFile(path, _, _, _)
+            | Self::AddSymlink(path, _)
+            | Self::AddSpecial(path, _)
+            | Self::RemoveFile(path)
+            | Self::RemoveDir(path)
+            | Self::UpdateMetadata(path, _, _) => vec![path],
+            Self::Move(from, to) => vec![from, to],
+        }
+    }
+    /// If this change is about a directory
+    pub fn is_dir(&self) -> bool {
+        matches!(
+            self,
+            Self::AddDir(..) | Self::RemoveDir(..) | Self::UpdateMetadata(_, true, _)
+        )
+    }
+    /// How many bytes (roughly) will be written to the target to apply this change
+    pub fn transfer_size(&self) -> u64 {
+        match self {
+            Self::AddFile(_, index_file) => index_file.size,
+            Self::UpdateFile(_, _, _, size) => *size,
+            _ => 0,
+        }
+    }
 }
@@ -1,49 +1,167 @@
 use std::{
+    cmp::Ordering,
     collections::HashMap,
     fs::{self, Metadata},
     io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::Path,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
-use crate::{repr_file::ReprFile, update_index::Settings};
+use crate::{delta::BlockHashes, repr_file::ReprFile, update_index::Settings};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct IndexFile {
     pub size: u64,
     pub last_modified: Option<u64>,
+    /// nanoseconds to be added to `last_modified`, unknown in indexes created before version 4
+    pub last_modified_nanos: Option<u32>,
+    /// hash of the file's contents, only recorded when using `--checksum`
+    pub hash: Option<String>,
+    /// permission bits, including setuid, setgid and sticky bits
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// `None` for regular files
+    pub special: Option<SpecialFile>,
+    /// the inode number in source, used to detect files and directories which were moved
+    pub inode: Option<u64>,
+    /// hashes of the file's blocks on the target, only recorded for large files,
+    /// so they can be updated by only writing the blocks which changed
+    pub blocks: Option<BlockHashes>,
+}
+
+/// Files which have no contents and are recreated on the target instead of being copied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialFile {
+    Fifo,
+    Socket,
+    /// a character device with this device id
+    CharDevice(u64),
+    /// a block device with this device id
+    BlockDevice(u64),
+}
+
+impl SpecialFile {
+    pub fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        let file_type = metadata.file_type();
+        if file_type.is_fifo() {
+            Some(Self::Fifo)
+        } else if file_type.is_socket() {
+            Some(Self::Socket)
+        } else if file_type.is_char_device() {
+            Some(Self::CharDevice(metadata.rdev()))
+        } else if file_type.is_block_device() {
+            Some(Self::BlockDevice(metadata.rdev()))
+        } else {
+            None
+        }
+    }
+    /// The name used in index files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fifo => "fifo",
+            Self::Socket => "socket",
+            Self::CharDevice(_) => "char",
+            Self::BlockDevice(_) => "block",
+        }
+    }
+    /// The device id, or 0 for fifos and sockets
+    pub fn rdev(&self) -> u64 {
+        match self {
+            Self::Fifo | Self::Socket => 0,
+            Self::CharDevice(rdev) | Self::BlockDevice(rdev) => *rdev,
+        }
+    }
 }
 
 impl IndexFile {
     pub fn should_be_updated(&self, prev: &Self, settings: &Settings) -> bool {
+        if self.special != prev.special {
+            return true;
+        }
+        if self.special.is_some() {
+            // no contents, changed timestamps are handled by `metadata_changed`
+            return false;
+        }
+        if settings.checksum
+            && let (Some(new), Some(old)) = (&self.hash, &prev.hash)
+        {
+            // the contents are known, so the timestamp doesn't matter
+            return self.size != prev.size || new != old;
+        }
         self.size != prev.size
             || ((!settings.ignore_timestamp)
                 && match (self.last_modified, prev.last_modified) {
-                    (Some(new), Some(old)) => {
-                        if new > old {
-                            true
-                        } else if new < old {
-                            !settings.dont_replace_newer
-                        } else {
-                            // same timestamp
-                            false
-                        }
-                    }
+                    (Some(_), Some(_)) => match self.cmp_timestamp(prev, settings) {
+                        Some(Ordering::Greater) => true,
+                        Some(Ordering::Less) => !settings.dont_replace_newer,
+                        // same timestamp
+                        Some(Ordering::Equal) | None => false,
+                    },
                     (Some(_), None) => !settings.dont_replace_if_timestamp_found,
                     (None, Some(_)) => settings.replace_if_timestamp_lost,
                     (None, None) => settings.replace_if_timestamp_unknown,
                 })
     }
+    /// Compares the timestamps, treating them as equal if they differ by less than the granularity.
+    /// If either timestamp has no nanoseconds, only full seconds are compared.
+    /// Returns `None` if either timestamp is unknown.
+    pub fn cmp_timestamp(&self, prev: &Self, settings: &Settings) -> Option<Ordering> {
+        let (new, old) = (self.last_modified?, prev.last_modified?);
+        let (new, old) = match (self.last_modified_nanos, prev.last_modified_nanos) {
+            (Some(new_nanos), Some(old_nanos)) => (
+                Duration::new(new, new_nanos),
+                Duration::new(old, old_nanos),
+            ),
+            _ => (Duration::from_secs(new), Duration::from_secs(old)),
+        };
+        if new.abs_diff(old) < settings.timestamp_granularity.max(Duration::from_nanos(1)) {
+            Some(Ordering::Equal)
+        } else {
+            Some(new.cmp(&old))
+        }
+    }
+    /// If the file's contents don't have to be copied again, this checks if
+    /// its permissions, owner or (for directories) modification time should still be updated.
+    /// `no_contents` is true for directories and special files, whose timestamps are only metadata.
+    pub fn metadata_changed(&self, prev: &Self, no_contents: bool, settings: &Settings) -> bool {
+        fn changed<T: PartialEq>(new: &Option<T>, old: &Option<T>) -> bool {
+            new.is_some() && new != old
+        }
+        changed(&self.mode, &prev.mode)
+            || changed(&self.inode, &prev.inode)
+            || changed(&self.uid, &prev.uid)
+            || changed(&self.gid, &prev.gid)
+            || ((no_contents || (settings.checksum && self.hash.is_some() && self.hash == prev.hash))
+                && !settings.ignore_timestamp
+                && self.last_modified.is_some()
+                && self.cmp_timestamp(prev, settings) != Some(Ordering::Equal))
+    }
     pub fn new_from_metadata(metadata: &Metadata) -> Self {
+        let last_modified = metadata
+            .modified()
+            .ok()
+            .and_then(|v| v.duration_since(SystemTime::UNIX_EPOCH).ok());
         Self {
             size: metadata.len(),
-            last_modified: metadata
-                .modified()
-                .ok()
-                .and_then(|v| v.duration_since(SystemTime::UNIX_EPOCH).ok())
-                .map(|v| v.as_secs()),
+            last_modified: last_modified.map(|v| v.as_secs()),
+            last_modified_nanos: last_modified.map(|v| v.subsec_nanos()),
+            hash: None,
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            special: SpecialFile::from_metadata(metadata),
+            inode: Some(metadata.ino()),
+            blocks: None,
         }
     }
+    /// reads the entire file, so only use this if `--checksum` was set
+    pub fn hash_file(path: &Path) -> io::Result<String> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(fs::File::open(path)?)?;
+        Ok(hasher.finalize().to_hex().to_string())
+    }
     pub fn from_path(path: &Path) -> io::Result<Result<Self, String>> {
         Ok(Self::load(&fs::read_to_string(path)?))
     }
@@ -55,18 +173,80 @@ impl ReprFile for IndexFile {
         if let Some(age) = self.last_modified {
             o.push_str(&format!("Age={}\n", age));
         }
+        if let Some(nanos) = self.last_modified_nanos {
+            o.push_str(&format!("AgeNs={}\n", nanos));
+        }
+        if let Some(hash) = &self.hash {
+            o.push_str(&format!("Hash={}\n", hash));
+        }
+        if let Some(mode) = self.mode {
+            o.push_str(&format!("Mode={:o}\n", mode));
+        }
+        if let Some(uid) = self.uid {
+            o.push_str(&format!("Uid={}\n", uid));
+        }
+        if let Some(gid) = self.gid {
+            o.push_str(&format!("Gid={}\n", gid));
+        }
+        if let Some(inode) = self.inode {
+            o.push_str(&format!("Ino={}\n", inode));
+        }
+        if let Some(special) = self.special {
+            o.push_str(&format!("Type={}\n", special.name()));
+            if let SpecialFile::CharDevice(rdev) | SpecialFile::BlockDevice(rdev) = special {
+                o.push_str(&format!("Rdev={}\n", rdev));
+            }
+        }
+        if let Some(blocks) = &self.blocks {
+            o.push_str(&format!("Blocks={}\n", blocks));
+        }
         o
     }
     fn load(src: &str) -> Result<Self, String> {
         let hm = HashMap::load(src)?;
         if let Some(len) = hm.get("Len").and_then(|len_str| len_str.parse().ok()) {
             let age = hm.get("Age").and_then(|lm_str| lm_str.parse().ok());
+            let age_nanos = hm
+                .get("AgeNs")
+                .and_then(|lm_str| lm_str.parse().ok())
+                .filter(|nanos| *nanos < 1_000_000_000);
+            let hash = hm.get("Hash").cloned();
+            let mode = hm
+                .get("Mode")
+                .and_then(|mode_str| u32::from_str_radix(mode_str, 8).ok());
+            let uid = hm.get("Uid").and_then(|uid_str| uid_str.parse().ok());
+            let gid = hm.get("Gid").and_then(|gid_str| gid_str.parse().ok());
+            let inode = hm.get("Ino").and_then(|ino_str| ino_str.parse().ok());
+            let blocks = hm
+                .get("Blocks")
+                .and_then(|blocks_str| blocks_str.parse().ok());
+            let rdev = || {
+                hm.get("Rdev")
+                    .and_then(|rdev_str| rdev_str.parse().ok())
+                    .ok_or_else(|| "no Rdev for device in IndexFile!".to_owned())
+            };
+            let special = match hm.get("Type").map(|t| t.as_str()) {
+                None => None,
+                Some("fifo") => Some(SpecialFile::Fifo),
+                Some("socket") => Some(SpecialFile::Socket),
+                Some("char") => Some(SpecialFile::CharDevice(rdev()?)),
+                Some("block") => Some(SpecialFile::BlockDevice(rdev()?)),
+                Some(other) => return Err(format!("unknown Type {other:?} in IndexFile!")),
+            };
             Ok(Self {
                 size: len,
                 last_modified: age,
+                last_modified_nanos: age_nanos,
+                hash,
+                mode,
+                uid,
+                gid,
+                special,
+                inode,
+                blocks,
             })
         } else {
-            return Err(format!("no Len in IndexFile!"));
+            Err("no Len in IndexFile!".to_owned())
         }
     }
 }
@@ -1,30 +1,276 @@
-use std::process::exit;
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, error::ErrorKind};
 
 use crate::{
-    apply_indexchanges::apply_indexchanges, config::Ignore, indexchanges::IndexChange,
-    update_index::perform_index_diff,
+    apply_indexchanges::{Applied, ApplySettings, apply_indexchanges},
+    args::{
+        ApplyArgs, BackupArgs, Command, OutputFormat, PlanArgs, RebuildIndexArgs, VerifyArgs,
+        WatchArgs,
+    },
+    config::Ignore,
+    guard::{DeleteLimits, check_removals},
+    identity::{check_target, mark_target},
+    index::Index,
+    indexchanges::IndexChange,
+    plan::Plan,
+    review::review,
+    source::{Source, parse_named_source},
+    update_index::{
+        DiffError, IndexDiff, Settings, diff_dirs, exclude_index_and_target, perform_index_diff,
+    },
+    verify::{Problem, verify},
+    watch::{Batch, Watcher},
 };
 
 mod apply_indexchanges;
 mod args;
 mod config;
+mod delta;
+mod guard;
+mod identity;
+mod index;
 mod indexchanges;
 mod indexfile;
+mod plan;
+mod rebuild_index;
 mod repr_file;
+mod resume;
+mod review;
+mod source;
 mod update_index;
+mod verify;
+mod watch;
 
 const EXIT_IGNORE_FAILED: u8 = 200;
+const EXIT_INDEX_FAILED: u8 = 10;
 const EXIT_DIFF_FAILED: u8 = 20;
+const EXIT_DIFF_INCOMPLETE: u8 = 21;
 const EXIT_APPLY_FAILED_ONE: u8 = 100;
 const EXIT_APPLY_FAILED_ALL: u8 = 200;
+const EXIT_VERIFY_FAILED: u8 = 30;
+const EXIT_VERIFY_FOUND_PROBLEMS: u8 = 31;
+const EXIT_REBUILD_FAILED: u8 = 40;
+const EXIT_PLAN_FAILED: u8 = 50;
+const EXIT_PLAN_OUTDATED: u8 = 51;
+const EXIT_WATCH_FAILED: u8 = 60;
+const EXIT_APPLY_BUDGET_REACHED: u8 = 70;
+const EXIT_TOO_MANY_REMOVALS: u8 = 80;
+const EXIT_WRONG_TARGET: u8 = 90;
 
 fn main() {
     // get args
-    let args = args::Args::parse();
+    let args::Args {
+        command,
+        backup: args,
+        settings,
+        apply_settings,
+        delete_limits,
+    } = args::Args::parse();
+    match command {
+        Some(Command::Plan(args)) => plan_main(args),
+        Some(Command::Apply(args)) => apply_main(args),
+        Some(Command::Verify(args)) => verify_main(args),
+        Some(Command::RebuildIndex(args)) => rebuild_index_main(args),
+        Some(Command::Watch(args)) => watch_main(args),
+        None => backup_main(
+            args.expect("backup args are required if there is no subcommand"),
+            settings,
+            apply_settings,
+            delete_limits,
+        ),
+    }
+}
+
+fn backup_main(
+    args: BackupArgs,
+    settings: Settings,
+    apply_settings: ApplySettings,
+    delete_limits: DeleteLimits,
+) {
+    let (source, target, mut index, mut diff) = diff_main(&args, &settings);
+    show_changes(&diff, &settings);
+    if diff.changes.is_empty() {
+        adopt_target_main(&mut index, &target, args.adopt_target);
+        exit_if_incomplete(&diff);
+        return;
+    }
+    // apply changes after confirming
+    if !args.noconfirm && !review(&mut diff, args.ignore.as_deref(), target.is_some()) {
+        return;
+    }
+    if diff.changes.is_empty() {
+        eprintln!("done! all changes were excluded.");
+        exit_if_incomplete(&diff);
+        return;
+    }
+    // only the changes which weren't excluded during the review are applied
+    check_removals_main(
+        &source,
+        &index,
+        &diff.changes,
+        &delete_limits,
+        !args.noconfirm,
+    );
+    apply_main_int(
+        &source,
+        &mut index,
+        &target,
+        &diff.changes,
+        diff.total_size,
+        &apply_settings,
+    );
+    exit_if_incomplete(&diff);
+}
+
+fn plan_main(args: PlanArgs) {
+    let (source, target, index, mut diff) = diff_main(&args.backup, &args.settings);
+    show_changes(&diff, &args.settings);
+    check_removals_main(&source, &index, &diff.changes, &args.delete_limits, false);
+    if target.is_none() {
+        eprintln!("[warn] You didn't set a `target` directory! Applying this plan will only update the index.");
+    }
+    let plan = Plan {
+        source,
+        index: index.path().to_owned(),
+        target,
+        generation: index.generation(),
+        total_size: diff.total_size,
+        changes: std::mem::take(&mut diff.changes),
+    };
+    if let Err(e) = plan.save_to(&args.output) {
+        eprintln!("Couldn't save plan to {:?}: {e}", args.output);
+        exit(EXIT_PLAN_FAILED as _);
+    }
+    eprintln!(
+        "[info] saved plan to {:?}, use `rembackup apply` to apply it.",
+        args.output
+    );
+    exit_if_incomplete(&diff);
+}
+
+fn apply_main(args: ApplyArgs) {
+    let mut plan = match Plan::load_from(&args.plan) {
+        Ok(Ok(plan)) => plan,
+        Ok(Err(e)) => {
+            eprintln!("Couldn't parse plan {:?}: {e}", args.plan);
+            exit(EXIT_PLAN_FAILED as _);
+        }
+        Err(e) => {
+            eprintln!("Couldn't load plan {:?}: {e}", args.plan);
+            exit(EXIT_PLAN_FAILED as _);
+        }
+    };
+    if let Err(e) = replace_sources(&mut plan.source, args.source) {
+        eprintln!("Couldn't use --source: {e}");
+        exit(EXIT_PLAN_FAILED as _);
+    }
+    if let Some(index) = args.index {
+        plan.index = index;
+    }
+    if args.target.is_some() {
+        plan.target = args.target;
+    }
+    if !plan.index.exists() && plan.generation != 0 {
+        eprintln!("Couldn't open index {:?}: it doesn't exist", plan.index);
+        exit(EXIT_INDEX_FAILED as _);
+    }
+    let mut index = match Index::open(&plan.index, None) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Couldn't open index {:?}: {e}", plan.index);
+            exit(EXIT_INDEX_FAILED as _);
+        }
+    };
+    if index.generation() != plan.generation {
+        eprintln!(
+            "The index {:?} was changed after the plan was made (generation {}, plan was made for generation {}).\nCreate a new plan using `rembackup plan`.",
+            plan.index,
+            index.generation(),
+            plan.generation
+        );
+        exit(EXIT_PLAN_OUTDATED as _);
+    }
+    if let Some(target) = &plan.target {
+        check_target_main(&index, target, args.adopt_target);
+    }
+    if plan.changes.is_empty() {
+        adopt_target_main(&mut index, &plan.target, args.adopt_target);
+        eprintln!("done! the plan contains no changes.");
+        return;
+    }
+    eprintln!("applying {} changes...", plan.changes.len());
+    apply_main_int(
+        &plan.source,
+        &mut index,
+        &plan.target,
+        &plan.changes,
+        plan.total_size,
+        &args.apply_settings,
+    );
+}
+
+/// Replaces the plan's source with `--source PATH`, or the named sources with `--source NAME=PATH`
+fn replace_sources(source: &mut Source, replacements: Vec<PathBuf>) -> Result<(), String> {
+    match source {
+        Source::Dir(dir) => match <[PathBuf; 1]>::try_from(replacements) {
+            Ok([replacement]) => *dir = replacement,
+            Err(replacements) if replacements.is_empty() => {}
+            Err(_) => return Err("the plan has only one source".to_owned()),
+        },
+        Source::Named(sources) => {
+            for replacement in replacements {
+                let replacement = replacement
+                    .to_str()
+                    .ok_or_else(|| format!("{replacement:?} isn't valid UTF-8"))?;
+                let (name, path) = parse_named_source(replacement)?;
+                let (_, old_path) = sources
+                    .iter_mut()
+                    .find(|(n, _)| *n == name)
+                    .ok_or_else(|| format!("the plan has no source named {name:?}"))?;
+                *old_path = path;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Opens the index and finds the changes, or exits if that fails.
+/// Returns the absolute source and target paths, the index, and the changes.
+fn diff_main(
+    args: &BackupArgs,
+    settings: &Settings,
+) -> (Source, Option<PathBuf>, Index, IndexDiff) {
     // index diff
     eprintln!("performing index diff...");
+    let (source, target, index, ignore) = open_main(args);
+    let diff = match perform_index_diff(
+        &source,
+        &index,
+        target.as_deref(),
+        ignore,
+        settings,
+        sort_by_size_largest(settings),
+    ) {
+        Ok(c) => c,
+        Err(e) => {
+            show_diff_error(&e);
+            exit(EXIT_DIFF_FAILED as _);
+        }
+    };
+    (source, target, index, diff)
+}
+
+/// Loads the ignore-file and opens the index, or exits if that fails.
+/// Returns the absolute source and target paths, the index, and what should be ignored.
+/// Exits if the paths are invalid, for example if `--source` is used together with <source>.
+fn open_main(args: &BackupArgs) -> (Source, Option<PathBuf>, Index, Ignore) {
+    let (source, index, target) = args
+        .paths()
+        .unwrap_or_else(|e| args::Args::command().error(ErrorKind::ArgumentConflict, e).exit());
     let cwd = match std::env::current_dir() {
         Ok(v) => Some(v),
         Err(e) => {
@@ -32,23 +278,17 @@ fn main() {
             None
         }
     };
-    let source = if args.source.is_absolute() {
-        args.source.clone()
+    let source = source.absolute(cwd.as_deref());
+    let index = if index.is_absolute() {
+        index
     } else {
         cwd.as_ref()
             .expect("tried to use a relative path when there is no valid CWD")
-            .join(&args.source)
+            .join(&index)
     };
-    let index = if args.index.is_absolute() {
-        args.index.clone()
-    } else {
-        cwd.as_ref()
-            .expect("tried to use a relative path when there is no valid CWD")
-            .join(&args.index)
-    };
-    let target = args.target.as_ref().map(|target| {
+    let target = target.map(|target| {
         if target.is_absolute() {
-            target.clone()
+            target
         } else {
             cwd.as_ref()
                 .expect("tried to use a relative path when there is no valid CWD")
@@ -72,143 +312,695 @@ fn main() {
     } else {
         Ignore(vec![])
     };
-    let (total_size, changes) = match perform_index_diff(
-        &source,
-        &index,
-        target.as_ref().map(|v| v.as_path()),
-        ignore,
-        &args.settings,
-        if args.settings.dont_sort {
-            None
-        } else {
-            Some(!args.settings.smallest_first)
-        },
-    ) {
-        Ok(c) => c,
-        Err((what, path, err)) => {
-            eprintln!(
-                "Failed to generate index diff:\n    {what}\n    {}\n    {err}",
-                path.to_string_lossy()
-            );
-            exit(EXIT_DIFF_FAILED as _);
+    let index = match Index::open(&index, args.index_format) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Couldn't open index {index:?}: {e}");
+            exit(EXIT_INDEX_FAILED as _);
         }
     };
-    if changes.is_empty() {
-        eprintln!("done! found no changes.");
+    if let Some(target) = &target {
+        check_target_main(&index, target, args.adopt_target);
+    }
+    (source, target, index, ignore)
+}
+
+fn sort_by_size_largest(settings: &Settings) -> Option<bool> {
+    if settings.dont_sort {
+        None
     } else {
-        eprintln!("done! found {} changes:", changes.len());
-        // display the changes
-        if args.settings.dont_reverse_output {
-            for change in &changes {
-                show_change(change, false);
+        Some(!settings.smallest_first)
+    }
+}
+fn show_diff_error((what, path, err): &DiffError) {
+    eprintln!(
+        "Failed to generate index diff:\n    {what}\n    {}\n    {err}",
+        path.to_string_lossy()
+    );
+}
+
+/// Backs up everything once, then keeps backing up the directories in which inotify found changes
+fn watch_main(args: WatchArgs) {
+    let WatchArgs {
+        quiet_period,
+        backup,
+        settings,
+        apply_settings,
+        delete_limits,
+    } = args;
+    let (source, target, mut index, mut ignore) = open_main(&backup);
+    if target.is_none() {
+        eprintln!(
+            "[warn] You didn't set a `target` directory! Changes will only be saved to the index."
+        );
+    }
+    exclude_index_and_target(&source, &index, target.as_deref(), &mut ignore);
+    // start watching before the first backup, so changes made during the backup aren't lost
+    let mut watcher = match Watcher::new(&source, &ignore, &settings) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Couldn't watch {source}: {e}");
+            exit(EXIT_WATCH_FAILED as _);
+        }
+    };
+    eprintln!("[info] watching {} directories", watcher.len());
+    let mut batch = Batch {
+        rescan: true,
+        ..Default::default()
+    };
+    loop {
+        let dirs = (!batch.rescan).then(|| batch.dirty.iter().cloned().collect::<Vec<_>>());
+        eprintln!(
+            "performing index diff ({})...",
+            match &dirs {
+                None => "everything".to_owned(),
+                Some(dirs) => format!("{} directories", dirs.len()),
             }
-        } else {
-            for change in changes.iter().rev() {
-                show_change(change, true);
+        );
+        let diff = diff_dirs(
+            &source,
+            &index,
+            &ignore,
+            &settings,
+            sort_by_size_largest(&settings),
+            dirs.as_deref(),
+        )
+        .and_then(|diff| {
+            let too_many_removals = check_removals(&source, &index, &diff.changes, &delete_limits)?;
+            Ok((diff, too_many_removals))
+        });
+        match diff {
+            Ok((diff, Some(reason))) => {
+                show_changes(&diff, &settings);
+                eprintln!(
+                    "[warn] {reason}\n[warn] these changes were not applied, they will be found again after the next change."
+                );
             }
-        }
-        fn show_change(change: &IndexChange, rev: bool) {
-            match change {
-                IndexChange::AddDir(v, new, s) => {
-                    let mut path_str = v.display().to_string();
-                    if !path_str.ends_with(['/', '\\']) {
-                        path_str.push('/');
+            Ok((diff, None)) => {
+                show_changes(&diff, &settings);
+                let Applied {
+                    failures,
+                    remaining,
+                } = if diff.changes.is_empty() {
+                    Applied {
+                        failures: 0,
+                        remaining: 0,
                     }
+                } else {
+                    let applied = apply_indexchanges(
+                        &source,
+                        &mut index,
+                        &target,
+                        &diff.changes,
+                        Some(diff.total_size as f64 / (1024 * 1024 * 1024) as f64),
+                        &apply_settings,
+                    );
+                    eprintln!("[info] encountered {} failures", applied.failures);
+                    applied
+                };
+                if remaining > 0 {
                     eprintln!(
-                        " {}{} {}    [{:.2} GiB]",
-                        if rev { "^" } else { "v" },
-                        if *new { ">>" } else { "> " },
-                        path_str,
-                        *s as f64 / (1024 * 1024 * 1024) as f64
+                        "[info] the remaining changes will be applied after the next change."
                     );
                 }
-                IndexChange::AddFile(v, f) => eprintln!(
-                    "  +  {}    ({:.3} GiB)",
-                    v.display(),
-                    f.size as f64 / (1024 * 1024 * 1024) as f64
-                ),
-                IndexChange::AddSymlink(v, link_target) => {
-                    eprintln!("  +  {}    (-> {})", v.display(), link_target.display())
-                }
-                IndexChange::RemoveFile(v) => eprintln!("  -  {}", v.display()),
-                IndexChange::RemoveDir(v) => {
-                    let mut path_str = v.display().to_string();
-                    if !path_str.ends_with(['/', '\\']) {
-                        path_str.push('/');
-                    }
-                    eprintln!(" [-] {}", path_str);
+                if failures == 0 && remaining == 0 && diff.errors.is_empty() {
+                    batch = Batch::default();
+                } else if failures > 0 || !diff.errors.is_empty() {
+                    eprintln!("[warn] the failed entries will be retried after the next change.");
                 }
             }
+            Err(e) => {
+                show_diff_error(&e);
+                eprintln!("[warn] this will be retried after the next change.");
+            }
         }
-        eprintln!(" - - - - -");
-        let add_dir_count = changes
-            .iter()
-            .filter(|c| matches!(c, IndexChange::AddDir(_, true, _)))
-            .count();
-        eprintln!(
-            " {}>> add directory | {add_dir_count}x",
-            if args.settings.dont_reverse_output {
-                "v"
-            } else {
-                "^"
+        // wait for the next change, then until nothing changed for `quiet_period`
+        let mut wait = |timeout, batch: &mut Batch| {
+            watcher.wait(timeout, batch).unwrap_or_else(|e| {
+                eprintln!("Couldn't watch {source}: {e}");
+                exit(EXIT_WATCH_FAILED as _);
+            })
+        };
+        loop {
+            wait(None, &mut batch);
+            while wait(Some(quiet_period), &mut batch) {}
+            // events for ignored entries, like the index, don't change the batch
+            if batch.rescan || !batch.dirty.is_empty() {
+                break;
             }
-        );
-        let add_file_count = changes
-            .iter()
-            .filter_map(|c| {
-                if let IndexChange::AddFile(_, f) = c {
-                    Some(f)
-                } else {
-                    None
+        }
+        if batch.rescan {
+            eprintln!("[warn] too many changes at once, rescanning everything.");
+            watcher.add_watches(Path::new(""));
+        }
+    }
+}
+
+fn show_changes(diff: &IndexDiff, settings: &Settings) {
+    if let OutputFormat::Json = settings.format {
+        return show_changes_json(diff);
+    }
+    let IndexDiff {
+        total_size: _,
+        changes,
+        skipped_mounts,
+        skipped_special,
+        errors,
+    } = diff;
+    if changes.is_empty() {
+        eprintln!("done! found no changes.");
+        show_skipped(skipped_mounts, *skipped_special);
+        show_errors(errors);
+        return;
+    }
+    eprintln!("done! found {} changes:", changes.len());
+    // display the changes
+    if settings.dont_reverse_output {
+        for change in changes {
+            show_change(change, false);
+        }
+    } else {
+        for change in changes.iter().rev() {
+            show_change(change, true);
+        }
+    }
+    fn show_change(change: &IndexChange, rev: bool) {
+        match change {
+            IndexChange::AddDir(v, new, s) => {
+                let mut path_str = v.display().to_string();
+                if !path_str.ends_with(['/', '\\']) {
+                    path_str.push('/');
                 }
-            })
-            .count();
-        let add_file_total_size_gib = total_size as f64 / (1024 * 1024 * 1024) as f64;
-        eprintln!("  +  add/update file | {add_file_count}x ({add_file_total_size_gib:.1} GiB)");
-        let remove_file_count = changes
-            .iter()
-            .filter(|c| matches!(c, IndexChange::RemoveFile(..)))
-            .count();
-        eprintln!("  -  remove file | {}x", remove_file_count);
-        let remove_dir_count = changes
-            .iter()
-            .filter(|c| matches!(c, IndexChange::RemoveDir(..)))
-            .count();
-        eprintln!(" [-] remove directory (and all contents!) | {remove_dir_count}x");
-        // apply changes after confirming
-        if !args.noconfirm {
-            loop {
-                if args.target.is_none() {
-                    eprintln!("[warn] You didn't set a `target` directory!\n[warn] Be careful not to update your index without actually applying the changes to the `target` filesystem!\nType 'Ok' and press enter to continue.");
-                } else {
-                    eprintln!("Exclude unwanted directories/files using --ignore,\nor press enter to apply the changes.");
+                eprintln!(
+                    " {}{} {}    [{:.2} GiB]",
+                    if rev { "^" } else { "v" },
+                    if *new { ">>" } else { "> " },
+                    path_str,
+                    *s as f64 / (1024 * 1024 * 1024) as f64
+                );
+            }
+            IndexChange::AddFile(v, f) => eprintln!(
+                "  +  {}    ({:.3} GiB)",
+                v.display(),
+                f.size as f64 / (1024 * 1024 * 1024) as f64
+            ),
+            IndexChange::UpdateFile(v, f, _, s) => eprintln!(
+                "  *  {}    ({:.3} of {:.3} GiB)",
+                v.display(),
+                *s as f64 / (1024 * 1024 * 1024) as f64,
+                f.size as f64 / (1024 * 1024 * 1024) as f64
+            ),
+            IndexChange::AddSymlink(v, link_target) => {
+                eprintln!("  +  {}    (-> {})", v.display(), link_target.display())
+            }
+            IndexChange::AddSpecial(v, f) => eprintln!(
+                "  +  {}    ({})",
+                v.display(),
+                f.special.map_or("special file", |s| s.name())
+            ),
+            IndexChange::Move(from, to) => {
+                eprintln!("  >  {}    (<- {})", to.display(), from.display())
+            }
+            IndexChange::RemoveFile(v) => eprintln!("  -  {}", v.display()),
+            IndexChange::RemoveDir(v) => {
+                let mut path_str = v.display().to_string();
+                if !path_str.ends_with(['/', '\\']) {
+                    path_str.push('/');
                 }
-                let line = if let Some(Ok(v)) = std::io::stdin().lines().next() {
-                    v
-                } else {
-                    return;
-                };
-                let line = line.trim().to_lowercase();
-                if line == "exit" {
-                    return;
-                } else if args.target.is_some() || line == "ok" {
-                    break;
+                eprintln!(" [-] {}", path_str);
+            }
+            IndexChange::UpdateMetadata(v, is_dir, _) => {
+                let mut path_str = v.display().to_string();
+                if *is_dir && !path_str.ends_with(['/', '\\']) {
+                    path_str.push('/');
                 }
+                eprintln!("  ~  {}", path_str);
             }
         }
-        let failure_count = apply_indexchanges(
-            &args.source,
-            &args.index,
-            &args.target,
-            &changes,
-            Some(add_file_total_size_gib),
+    }
+    eprintln!(" - - - - -");
+    let add_dir_count = changes
+        .iter()
+        .filter(|c| matches!(c, IndexChange::AddDir(_, true, _)))
+        .count();
+    eprintln!(
+        " {}>> add directory | {add_dir_count}x",
+        if settings.dont_reverse_output {
+            "v"
+        } else {
+            "^"
+        }
+    );
+    let (add_file_count, add_file_size) = changes
+        .iter()
+        .filter_map(|c| match c {
+            IndexChange::AddFile(_, f) => Some(f.size),
+            _ => None,
+        })
+        .fold((0, 0), |(count, size), s| (count + 1, size + s));
+    let add_file_size_gib = add_file_size as f64 / (1024 * 1024 * 1024) as f64;
+    eprintln!("  +  add/update file | {add_file_count}x ({add_file_size_gib:.1} GiB)");
+    let remove_file_count = changes
+        .iter()
+        .filter(|c| matches!(c, IndexChange::RemoveFile(..)))
+        .count();
+    let (update_file_count, update_file_size) = changes
+        .iter()
+        .filter_map(|c| match c {
+            IndexChange::UpdateFile(_, _, _, s) => Some(*s),
+            _ => None,
+        })
+        .fold((0, 0), |(count, size), s| (count + 1, size + s));
+    let update_file_size_gib = update_file_size as f64 / (1024 * 1024 * 1024) as f64;
+    eprintln!(
+        "  *  update changed blocks of file | {update_file_count}x ({update_file_size_gib:.1} GiB)"
+    );
+    let add_special_count = changes
+        .iter()
+        .filter(|c| matches!(c, IndexChange::AddSpecial(..)))
+        .count();
+    eprintln!("  +  add fifo/socket/device | {add_special_count}x");
+    let move_count = changes
+        .iter()
+        .filter(|c| matches!(c, IndexChange::Move(..)))
+        .count();
+    eprintln!("  >  move file/directory | {move_count}x");
+    eprintln!("  -  remove file | {}x", remove_file_count);
+    let remove_dir_count = changes
+        .iter()
+        .filter(|c| matches!(c, IndexChange::RemoveDir(..)))
+        .count();
+    eprintln!(" [-] remove directory (and all contents!) | {remove_dir_count}x");
+    let update_metadata_count = changes
+        .iter()
+        .filter(|c| matches!(c, IndexChange::UpdateMetadata(..)))
+        .count();
+    eprintln!("  ~  update permissions/owner/timestamp | {update_metadata_count}x");
+    show_skipped(skipped_mounts, *skipped_special);
+    show_errors(errors);
+}
+fn show_skipped(skipped_mounts: &[PathBuf], skipped_special: usize) {
+    if skipped_special > 0 {
+        eprintln!("[info] skipped {skipped_special} fifos/sockets/devices (--skip-special)");
+    }
+    if skipped_mounts.is_empty() {
+        return;
+    }
+    eprintln!(
+        "[info] skipped {} directories on other filesystems (--one-file-system):",
+        skipped_mounts.len()
+    );
+    for path in skipped_mounts {
+        eprintln!("  |  {}", path.display());
+    }
+}
+
+fn show_errors(errors: &[DiffError]) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!(
+        "[warn] couldn't read {} entries (--keep-going), they will be left untouched in the backup:",
+        errors.len()
+    );
+    for (what, path, err) in errors {
+        eprintln!("  !  {}    ({what}: {err})", path.display());
+    }
+}
+
+/// Exits if `target` doesn't contain the backup made with `index`, unless it should be adopted.
+/// Doesn't write anything, the target is marked when changes are applied (see `adopt_target_main`).
+fn check_target_main(index: &Index, target: &Path, adopt: bool) {
+    match check_target(index, target) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) if adopt => {
+            eprintln!(
+                "[info] {e}\n[info] the target will be adopted because of --adopt-target when the backup is applied."
+            );
+        }
+        Ok(Err(e)) => {
+            eprintln!(
+                "{e}\nIs this the right target? If it is, use --adopt-target to use it with this index."
+            );
+            exit(EXIT_WRONG_TARGET as _);
+        }
+        Err(e) => {
+            eprintln!("Couldn't check target {target:?}: {e}");
+            exit(EXIT_WRONG_TARGET as _);
+        }
+    }
+}
+
+/// Marks `target` as belonging to `index` with --adopt-target when there are no changes to apply,
+/// because applying changes marks the target anyway
+fn adopt_target_main(index: &mut Index, target: &Option<PathBuf>, adopt: bool) {
+    if let Some(target) = target
+        && adopt
+        && let Err(e) = mark_target(index, target)
+    {
+        eprintln!("Couldn't adopt target {target:?}: {e}");
+        exit(EXIT_WRONG_TARGET as _);
+    }
+}
+
+/// Exits if the changes remove more than the `limits` allow, unless the user is asked and confirms it
+fn check_removals_main(
+    source: &Source,
+    index: &Index,
+    changes: &[IndexChange],
+    limits: &DeleteLimits,
+    ask: bool,
+) {
+    let reason = match check_removals(source, index, changes, limits) {
+        Ok(None) => return,
+        Ok(Some(reason)) => reason,
+        Err(e) => {
+            show_diff_error(&e);
+            exit(EXIT_INDEX_FAILED as _);
+        }
+    };
+    eprintln!("[warn] {reason}");
+    if ask {
+        eprintln!(
+            "Type 'delete' and press enter if you really want to remove these entries from the backup."
         );
-        eprintln!("[info] encountered {failure_count} failures");
-        if failure_count > 0 {
-            exit(
-                (EXIT_APPLY_FAILED_ONE as u64 + failure_count.ilog2() as u64)
-                    .min(EXIT_APPLY_FAILED_ALL as u64) as _,
+        if let Some(Ok(line)) = std::io::stdin().lines().next()
+            && line.trim().eq_ignore_ascii_case("delete")
+        {
+            return;
+        }
+    } else {
+        eprintln!(
+            "Nothing was changed. Use --allow-mass-delete if these entries should be removed."
+        );
+    }
+    exit(EXIT_TOO_MANY_REMOVALS as _);
+}
+
+/// Exits if some entries couldn't be read because of `--keep-going`, after everything else is done
+fn exit_if_incomplete(diff: &IndexDiff) {
+    if !diff.errors.is_empty() {
+        eprintln!(
+            "[warn] the backup is incomplete, {} entries couldn't be read (see above).",
+            diff.errors.len()
+        );
+        exit(EXIT_DIFF_INCOMPLETE as _);
+    }
+}
+
+fn show_changes_json(diff: &IndexDiff) {
+    let IndexDiff {
+        total_size,
+        changes,
+        skipped_mounts,
+        skipped_special,
+        errors,
+    } = diff;
+    let mut add_dir = 0;
+    let mut add_file = 0;
+    let mut update_file = 0;
+    let mut add_symlink = 0;
+    let mut add_special = 0;
+    let mut moves = 0;
+    let mut remove_file = 0;
+    let mut remove_dir = 0;
+    let mut update_metadata = 0;
+    for change in changes {
+        let line = match change {
+            IndexChange::AddDir(path, new, size) => {
+                if *new {
+                    add_dir += 1;
+                }
+                format!(
+                    r#"{{"type":"add_dir","path":{},"new":{new},"size":{size}}}"#,
+                    json_string(path)
+                )
+            }
+            IndexChange::AddFile(path, file) => {
+                add_file += 1;
+                format!(
+                    r#"{{"type":"add_file","path":{},"size":{}}}"#,
+                    json_string(path),
+                    file.size
+                )
+            }
+            IndexChange::UpdateFile(path, file, _, size) => {
+                update_file += 1;
+                format!(
+                    r#"{{"type":"update_file","path":{},"size":{size},"file_size":{}}}"#,
+                    json_string(path),
+                    file.size
+                )
+            }
+            IndexChange::AddSymlink(path, link_target) => {
+                add_symlink += 1;
+                format!(
+                    r#"{{"type":"add_symlink","path":{},"link":{}}}"#,
+                    json_string(path),
+                    json_string(link_target)
+                )
+            }
+            IndexChange::AddSpecial(path, file) => {
+                add_special += 1;
+                format!(
+                    r#"{{"type":"add_special","path":{},"kind":"{}"}}"#,
+                    json_string(path),
+                    file.special.map_or("unknown", |s| s.name())
+                )
+            }
+            IndexChange::Move(from, to) => {
+                moves += 1;
+                format!(
+                    r#"{{"type":"move","from":{},"path":{}}}"#,
+                    json_string(from),
+                    json_string(to)
+                )
+            }
+            IndexChange::RemoveFile(path) => {
+                remove_file += 1;
+                format!(r#"{{"type":"remove_file","path":{}}}"#, json_string(path))
+            }
+            IndexChange::RemoveDir(path) => {
+                remove_dir += 1;
+                format!(r#"{{"type":"remove_dir","path":{}}}"#, json_string(path))
+            }
+            IndexChange::UpdateMetadata(path, is_dir, _) => {
+                update_metadata += 1;
+                format!(
+                    r#"{{"type":"update_metadata","path":{},"dir":{is_dir}}}"#,
+                    json_string(path)
+                )
+            }
+        };
+        println!("{line}");
+    }
+    for path in skipped_mounts {
+        println!(
+            r#"{{"type":"skipped_mount","path":{}}}"#,
+            json_string(path)
+        );
+    }
+    for (what, path, err) in errors {
+        println!(
+            r#"{{"type":"error","path":{},"what":{},"error":{}}}"#,
+            json_string(path),
+            json_str(what),
+            json_str(&err.to_string())
+        );
+    }
+    println!(
+        r#"{{"type":"summary","changes":{},"add_dir":{add_dir},"add_file":{add_file},"update_file":{update_file},"add_file_size":{total_size},"add_symlink":{add_symlink},"add_special":{add_special},"move":{moves},"remove_file":{remove_file},"remove_dir":{remove_dir},"update_metadata":{update_metadata},"skipped_mounts":{},"skipped_special":{skipped_special},"errors":{}}}"#,
+        changes.len(),
+        skipped_mounts.len(),
+        errors.len()
+    );
+}
+/// Paths which aren't valid UTF-8 are converted lossily
+fn json_string(path: &Path) -> String {
+    json_str(&path.to_string_lossy())
+}
+fn json_str(s: &str) -> String {
+    let mut o = String::from('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => o.push_str("\\\""),
+            '\\' => o.push_str("\\\\"),
+            '\n' => o.push_str("\\n"),
+            '\t' => o.push_str("\\t"),
+            '\r' => o.push_str("\\r"),
+            ch if ch.is_control() => o.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => o.push(ch),
+        }
+    }
+    o.push('"');
+    o
+}
+
+/// Applies the changes and exits if any of them failed
+fn apply_main_int(
+    source: &Source,
+    index: &mut Index,
+    target: &Option<PathBuf>,
+    changes: &[IndexChange],
+    total_size: u64,
+    apply_settings: &ApplySettings,
+) {
+    let Applied {
+        failures: failure_count,
+        remaining,
+    } = apply_indexchanges(
+        source,
+        index,
+        target,
+        changes,
+        Some(total_size as f64 / (1024 * 1024 * 1024) as f64),
+        apply_settings,
+    );
+    eprintln!("[info] encountered {failure_count} failures");
+    if remaining > 0 {
+        eprintln!(
+            "[info] {remaining} changes weren't applied yet, run rembackup again to apply them."
+        );
+    }
+    if failure_count > 0 {
+        exit(
+            (EXIT_APPLY_FAILED_ONE as u64 + failure_count.ilog2() as u64)
+                .min(EXIT_APPLY_FAILED_ALL as u64) as _,
+        );
+    }
+    if remaining > 0 {
+        exit(EXIT_APPLY_BUDGET_REACHED as _);
+    }
+}
+
+fn verify_main(args: VerifyArgs) {
+    if !args.index.exists() {
+        eprintln!("Couldn't open index {:?}: it doesn't exist", args.index);
+        exit(EXIT_INDEX_FAILED as _);
+    }
+    let mut index = match Index::open(&args.index, None) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Couldn't open index {:?}: {e}", args.index);
+            exit(EXIT_INDEX_FAILED as _);
+        }
+    };
+    match check_target(&index, &args.target) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("[warn] {e}"),
+        Err(e) => eprintln!("[warn] couldn't check target {:?}: {e}", args.target),
+    }
+    let settings = args.timestamps.settings();
+    eprintln!("verifying target...");
+    let problems = match verify(&index, &args.target, &settings) {
+        Ok(problems) => problems,
+        Err((what, path, err)) => {
+            eprintln!(
+                "Failed to verify target:\n    {what}\n    {}\n    {err}",
+                path.to_string_lossy()
             );
+            exit(EXIT_VERIFY_FAILED as _);
         }
+    };
+    if problems.is_empty() {
+        eprintln!("done! target matches the index.");
+        return;
+    }
+    eprintln!("done! found {} problems:", problems.len());
+    for (path, problem) in &problems {
+        let mut path_str = path.display().to_string();
+        if matches!(problem, Problem::Missing(true) | Problem::WrongType(true))
+            && !path_str.ends_with(['/', '\\'])
+        {
+            path_str.push('/');
+        }
+        match problem {
+            Problem::Missing(_) => eprintln!("  missing       {path_str}"),
+            Problem::WrongType(true) => {
+                eprintln!("  wrong type    {path_str}    (index: directory, target: file)")
+            }
+            Problem::WrongType(false) => {
+                eprintln!("  wrong type    {path_str}    (index: file, target: something else)")
+            }
+            Problem::WrongSize(index_size, target_size) => eprintln!(
+                "  wrong size    {path_str}    (index: {index_size} bytes, target: {target_size} bytes)"
+            ),
+            Problem::WrongTimestamp => eprintln!("  wrong time    {path_str}"),
+            Problem::WrongSymlink(index_link, target_link) => eprintln!(
+                "  wrong link    {path_str}    (index: -> {}, target: -> {})",
+                index_link.display(),
+                target_link.display()
+            ),
+            Problem::NotInIndex => eprintln!("  not in index  {path_str}"),
+        }
+    }
+    if args.repair {
+        let removed = verify::repair(&mut index, &args.target, &problems);
+        eprintln!(
+            "[info] removed {removed} entries from the index, they will be copied again during the next backup."
+        );
+    } else {
+        eprintln!("run with --repair to copy the broken entries again during the next backup.");
+    }
+    exit(EXIT_VERIFY_FOUND_PROBLEMS as _);
+}
+
+fn rebuild_index_main(args: RebuildIndexArgs) {
+    let (source, index_path, target) = args.paths().unwrap_or_else(|e| {
+        args::Args::command()
+            .error(ErrorKind::ArgumentConflict, e)
+            .exit()
+    });
+    if index_path.exists() {
+        eprintln!(
+            "The index {index_path:?} already exists. Remove it or choose a different path for the new index."
+        );
+        exit(EXIT_INDEX_FAILED as _);
+    }
+    let mut index = match Index::open(&index_path, Some(args.index_format)) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Couldn't create index {index_path:?}: {e}");
+            exit(EXIT_INDEX_FAILED as _);
+        }
+    };
+    eprintln!("rebuilding index...");
+    let stats = match rebuild_index::rebuild_index(
+        &source,
+        &target,
+        &mut index,
+        &args.timestamps.settings(),
+        args.compare_content,
+    ) {
+        Ok(stats) => stats,
+        Err((what, path, err)) => {
+            eprintln!(
+                "Failed to rebuild index:\n    {what}\n    {}\n    {err}",
+                path.to_string_lossy()
+            );
+            exit(EXIT_REBUILD_FAILED as _);
+        }
+    };
+    // the new index describes the backup in target, so it may be used with it
+    if let Err(e) = mark_target(&mut index, &target) {
+        eprintln!("Couldn't mark {target:?} as the target of {index_path:?}: {e}");
+        exit(EXIT_REBUILD_FAILED as _);
+    }
+    if let Err(e) = index.save() {
+        eprintln!("Couldn't save index {index_path:?}: {e}");
+        exit(EXIT_REBUILD_FAILED as _);
     }
+    eprintln!("done! rebuilt index:");
+    eprintln!(
+        "  {} files/symlinks are the same in source and target",
+        stats.same
+    );
+    eprintln!(
+        "  {} files/symlinks are different and will be copied during the next backup",
+        stats.different
+    );
+    eprintln!(
+        "  {} files/directories only exist in target and will not be touched by future backups",
+        stats.not_in_source
+    );
 }
@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs, io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    indexchanges::IndexChange,
+    indexfile::IndexFile,
+    repr_file::ReprFile,
+    source::{Source, check_name, named_sources},
+};
+
+/// The first line of every plan file
+const PLAN_HEADER: &str = "rembackup-plan 1";
+
+/// Changes which were computed by `rembackup plan` and can be applied later by `rembackup apply`.
+///
+/// A plan file starts with `PLAN_HEADER`, followed by `Key=Value` lines and an empty line.
+/// After that, there is one change per line, with its fields separated by tabs.
+/// Paths are escaped (see `escape`), so they can't contain tabs or newlines.
+/// Named sources are saved as one `Source.NAME=PATH` line each.
+pub struct Plan {
+    pub source: Source,
+    pub index: PathBuf,
+    pub target: Option<PathBuf>,
+    /// The index's generation when the plan was made.
+    /// If the index has been changed since then, the plan can't be applied.
+    pub generation: u64,
+    /// The total size of all files which will be copied
+    pub total_size: u64,
+    pub changes: Vec<IndexChange>,
+}
+
+impl Plan {
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.save())
+    }
+    pub fn load_from(path: &Path) -> io::Result<Result<Self, String>> {
+        Ok(Self::load(&fs::read_to_string(path)?))
+    }
+}
+
+impl ReprFile for Plan {
+    fn save(&self) -> String {
+        let mut o = format!("{PLAN_HEADER}\n");
+        match &self.source {
+            Source::Dir(dir) => o.push_str(&format!("Source={}\n", escape(dir))),
+            Source::Named(sources) => {
+                for (name, path) in sources {
+                    o.push_str(&format!("Source.{name}={}\n", escape(path)));
+                }
+            }
+        }
+        o.push_str(&format!("Index={}\n", escape(&self.index)));
+        if let Some(target) = &self.target {
+            o.push_str(&format!("Target={}\n", escape(target)));
+        }
+        o.push_str(&format!("Generation={}\n", self.generation));
+        o.push_str(&format!("Size={}\n", self.total_size));
+        o.push('\n');
+        for change in &self.changes {
+            let line = match change {
+                IndexChange::AddDir(path, new, size) => {
+                    format!("AddDir\t{}\t{}\t{size}", escape(path), *new as u8)
+                }
+                IndexChange::AddFile(path, file) => {
+                    format!("AddFile\t{}\t{}", escape(path), save_index_file(file))
+                }
+                IndexChange::UpdateFile(path, file, old, size) => format!(
+                    "UpdateFile\t{}\t{}\t{}\t{size}",
+                    escape(path),
+                    save_index_file(file),
+                    save_index_file(old)
+                ),
+                IndexChange::AddSymlink(path, link) => {
+                    format!("AddSymlink\t{}\t{}", escape(path), escape(link))
+                }
+                IndexChange::AddSpecial(path, file) => {
+                    format!("AddSpecial\t{}\t{}", escape(path), save_index_file(file))
+                }
+                IndexChange::Move(from, to) => {
+                    format!("Move\t{}\t{}", escape(from), escape(to))
+                }
+                IndexChange::RemoveFile(path) => format!("RemoveFile\t{}", escape(path)),
+                IndexChange::RemoveDir(path) => format!("RemoveDir\t{}", escape(path)),
+                IndexChange::UpdateMetadata(path, is_dir, file) => format!(
+                    "UpdateMetadata\t{}\t{}\t{}",
+                    escape(path),
+                    *is_dir as u8,
+                    save_index_file(file)
+                ),
+            };
+            o.push_str(&line);
+            o.push('\n');
+        }
+        o
+    }
+    fn load(src: &str) -> Result<Self, String> {
+        let mut lines = src.lines();
+        if lines.next() != Some(PLAN_HEADER) {
+            return Err(format!("not a plan file (first line should be {PLAN_HEADER:?})"));
+        }
+        let header = lines
+            .by_ref()
+            .take_while(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let header = HashMap::<String, String>::load(&header)?;
+        let get = |key: &str| {
+            header
+                .get(key)
+                .ok_or_else(|| format!("no {key} in plan header"))
+        };
+        let number = |key: &str| {
+            get(key)?
+                .parse::<u64>()
+                .map_err(|e| format!("invalid {key} in plan header: {e}"))
+        };
+        let source = if let Some(dir) = header.get("Source") {
+            Source::Dir(unescape(dir)?)
+        } else {
+            let sources = header
+                .iter()
+                .filter_map(|(key, path)| Some((key.strip_prefix("Source.")?, path)))
+                .map(|(name, path)| {
+                    check_name(name)?;
+                    Ok((name.to_owned(), unescape(path)?))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            if sources.is_empty() {
+                return Err("no Source in plan header".to_owned());
+            }
+            named_sources(sources)?
+        };
+        let index = unescape(get("Index")?)?;
+        let target = header.get("Target").map(|t| unescape(t)).transpose()?;
+        let generation = number("Generation")?;
+        let total_size = number("Size")?;
+        let changes = lines
+            .enumerate()
+            .map(|(i, line)| {
+                load_change(line).map_err(|e| format!("invalid change {}: {e}", i + 1))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            source,
+            index,
+            target,
+            generation,
+            total_size,
+            changes,
+        })
+    }
+}
+
+fn load_change(line: &str) -> Result<IndexChange, String> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let bool_field = |field: &str| match field {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(format!("expected 0 or 1, found {field:?}")),
+    };
+    Ok(match fields.as_slice() {
+        ["AddDir", path, new, size] => IndexChange::AddDir(
+            unescape(path)?,
+            bool_field(new)?,
+            size.parse().map_err(|e| format!("invalid size: {e}"))?,
+        ),
+        ["AddFile", path, file] => IndexChange::AddFile(unescape(path)?, load_index_file(file)?),
+        ["UpdateFile", path, file, old, size] => IndexChange::UpdateFile(
+            unescape(path)?,
+            load_index_file(file)?,
+            load_index_file(old)?,
+            size.parse().map_err(|e| format!("invalid size: {e}"))?,
+        ),
+        ["AddSymlink", path, link] => IndexChange::AddSymlink(unescape(path)?, unescape(link)?),
+        ["AddSpecial", path, file] => {
+            IndexChange::AddSpecial(unescape(path)?, load_index_file(file)?)
+        }
+        ["Move", from, to] => IndexChange::Move(unescape(from)?, unescape(to)?),
+        ["RemoveFile", path] => IndexChange::RemoveFile(unescape(path)?),
+        ["RemoveDir", path] => IndexChange::RemoveDir(unescape(path)?),
+        ["UpdateMetadata", path, is_dir, file] => IndexChange::UpdateMetadata(
+            unescape(path)?,
+            bool_field(is_dir)?,
+            load_index_file(file)?,
+        ),
+        _ => return Err(format!("unknown change {line:?}")),
+    })
+}
+
+/// `IndexFile`s are saved on a single line, with spaces instead of newlines
+fn save_index_file(file: &IndexFile) -> String {
+    file.save().trim_end().replace('\n', " ")
+}
+fn load_index_file(src: &str) -> Result<IndexFile, String> {
+    IndexFile::load(&src.replace(' ', "\n"))
+}
+
+/// Escapes backslashes, tabs and newlines, and bytes which aren't valid UTF-8 (as `\xHH`)
+fn escape(path: &Path) -> String {
+    let mut o = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            match ch {
+                '\\' => o.push_str("\\\\"),
+                '\t' => o.push_str("\\t"),
+                '\n' => o.push_str("\\n"),
+                '\r' => o.push_str("\\r"),
+                ch => o.push(ch),
+            }
+        }
+        for byte in chunk.invalid() {
+            o.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    o
+}
+fn unescape(src: &str) -> Result<PathBuf, String> {
+    let mut o = Vec::with_capacity(src.len());
+    let mut chars = src.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            o.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => o.push(b'\\'),
+            Some('t') => o.push(b'\t'),
+            Some('n') => o.push(b'\n'),
+            Some('r') => o.push(b'\r'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                o.push(
+                    u8::from_str_radix(&hex, 16)
+                        .map_err(|_| format!("invalid escape \\x{hex} in {src:?}"))?,
+                );
+            }
+            other => return Err(format!("invalid escape {other:?} in {src:?}")),
+        }
+    }
+    Ok(OsString::from_vec(o).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexfile::SpecialFile;
+    use std::ffi::OsStr;
+
+    fn index_file(size: u64) -> IndexFile {
+        IndexFile {
+            size,
+            last_modified: Some(1_700_000_000),
+            last_modified_nanos: Some(123),
+            hash: None,
+            mode: Some(0o644),
+            uid: Some(1000),
+            gid: Some(1000),
+            special: None,
+            inode: Some(42),
+            blocks: None,
+        }
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        for path in [
+            &b"plain/path"[..],
+            b"tab\there/new\nline/cr\rx",
+            b"back\\slash\\x41\\t",
+            b"invalid \xff\xfe utf-8",
+            "unicode \u{e9}\u{1f600}".as_bytes(),
+        ] {
+            let path = Path::new(OsStr::from_bytes(path));
+            let escaped = escape(path);
+            assert!(!escaped.contains(['\t', '\n', '\r']), "{escaped:?}");
+            assert_eq!(unescape(&escaped).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn unescape_rejects_invalid_escapes() {
+        assert!(unescape("a\\qb").is_err());
+        assert!(unescape("trailing\\").is_err());
+        assert!(unescape("\\xzz").is_err());
+    }
+
+    #[test]
+    fn plan_round_trip() {
+        let mut special = index_file(0);
+        special.special = Some(SpecialFile::CharDevice(259));
+        let changes = vec![
+            IndexChange::AddDir("dir".into(), true, 10),
+            IndexChange::AddFile("dir/a b\tc".into(), index_file(10)),
+            IndexChange::UpdateFile("big".into(), index_file(20), index_file(19), 4),
+            IndexChange::AddSymlink("link".into(), "../tar\nget".into()),
+            IndexChange::AddSpecial("dev".into(), special),
+            IndexChange::Move("old".into(), "new".into()),
+            IndexChange::RemoveFile("gone".into()),
+            IndexChange::RemoveDir("gone dir".into()),
+            IndexChange::UpdateMetadata("dir".into(), true, index_file(0)),
+        ];
+        let plan = Plan {
+            source: Source::Named(vec![
+                ("docs".to_owned(), "/home/user/docs".into()),
+                ("etc".to_owned(), "/etc".into()),
+            ]),
+            index: "/backup/in\tdex".into(),
+            target: Some("/mnt/backup".into()),
+            generation: 7,
+            total_size: 30,
+            changes,
+        };
+        let saved = plan.save();
+        let loaded = Plan::load(&saved).unwrap();
+        assert_eq!(loaded.save(), saved);
+        assert_eq!(loaded.index, plan.index);
+        assert_eq!(loaded.target, plan.target);
+        assert_eq!(loaded.generation, 7);
+        assert_eq!(loaded.total_size, 30);
+        assert_eq!(loaded.changes.len(), plan.changes.len());
+        assert!(matches!(&loaded.source, Source::Named(sources) if sources.len() == 2));
+    }
+
+    #[test]
+    fn plan_without_target() {
+        let plan = Plan {
+            source: Source::Dir("/src".into()),
+            index: "/index".into(),
+            target: None,
+            generation: 0,
+            total_size: 0,
+            changes: vec![],
+        };
+        let loaded = Plan::load(&plan.save()).unwrap();
+        assert!(matches!(&loaded.source, Source::Dir(dir) if dir == Path::new("/src")));
+        assert_eq!(loaded.target, None);
+        assert!(loaded.changes.is_empty());
+    }
+
+    #[test]
+    fn plan_header_errors() {
+        assert!(Plan::load("").is_err());
+        assert!(Plan::load("rembackup-plan 2\nSource=/s\n").is_err());
+        let without_index = format!("{PLAN_HEADER}\nSource=/s\nGeneration=0\nSize=0\n\n");
+        assert!(Plan::load(&without_index).is_err());
+        let bad_size = format!("{PLAN_HEADER}\nSource=/s\nIndex=/i\nGeneration=0\nSize=x\n\n");
+        assert!(Plan::load(&bad_size).is_err());
+        let bad_change =
+            format!("{PLAN_HEADER}\nSource=/s\nIndex=/i\nGeneration=0\nSize=0\n\nFoo\tbar\n");
+        assert!(Plan::load(&bad_change).is_err());
+    }
+}
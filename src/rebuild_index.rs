@@ -0,0 +1,219 @@
+use std::{
+    cmp::Ordering,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    index::{self, Index},
+    indexfile::{IndexFile, SpecialFile},
+    source::Source,
+    update_index::{DiffError, Settings},
+};
+
+#[derive(Default)]
+pub struct RebuildStats {
+    /// files and symlinks which were added to the index
+    pub same: usize,
+    /// files and symlinks which exist in both source and target, but aren't the same
+    pub different: usize,
+    /// entries which only exist in target
+    pub not_in_source: usize,
+}
+
+/// Walks `target` and adds every entry which matches the corresponding entry in `source` to `index`.
+/// With named sources, each sub-directory of `target` is compared with the source of the same name.
+/// Files are compared by size and modification time, or by their contents if `compare_content` is set.
+///
+/// Permissions, owner and directory timestamps are taken from `target`,
+/// so if they differ from `source`, the next backup will update them.
+pub fn rebuild_index(
+    source: &Source,
+    target: &Path,
+    index: &mut Index,
+    settings: &Settings,
+    compare_content: bool,
+) -> Result<RebuildStats, DiffError> {
+    let mut stats = RebuildStats::default();
+    rec(
+        source,
+        target,
+        Path::new(""),
+        index,
+        settings,
+        compare_content,
+        &mut stats,
+    )?;
+    Ok(stats)
+}
+
+fn rec(
+    source: &Source,
+    target: &Path,
+    rel_path: &Path,
+    index: &mut Index,
+    settings: &Settings,
+    compare_content: bool,
+    stats: &mut RebuildStats,
+) -> Result<(), DiffError> {
+    let index_err = |what: &str, rel_path: &Path, index: &Index, e: io::Error| {
+        (what.to_owned(), index.path().join(rel_path), e)
+    };
+    let target_dir = target.join(rel_path);
+    let dir_metadata = fs::symlink_metadata(&target_dir)
+        .map_err(|e| ("getting metadata".to_owned(), target_dir.clone(), e))?;
+    index
+        .create_dir(rel_path)
+        .map_err(|e| index_err("creating directory in index", rel_path, index, e))?;
+    let mut dir_meta = IndexFile::new_from_metadata(&dir_metadata);
+    dir_meta.size = 0;
+    index
+        .write_dir_metadata(rel_path, &dir_meta)
+        .map_err(|e| index_err("writing directory metadata to index", rel_path, index, e))?;
+    let mut entries = fs::read_dir(&target_dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| ("getting entries".to_owned(), target_dir.clone(), e))?;
+    entries.sort_unstable();
+    for name in entries {
+        let rel_path = rel_path.join(name);
+        if index::is_reserved(&rel_path) {
+            continue;
+        }
+        let target_path = target.join(&rel_path);
+        let target_metadata = fs::symlink_metadata(&target_path)
+            .map_err(|e| ("getting metadata".to_owned(), target_path.clone(), e))?;
+        // entries in the root of named sources which aren't one of the sources
+        let Some(source_path) = source.path(&rel_path) else {
+            stats.not_in_source += 1;
+            continue;
+        };
+        let source_metadata = match fs::symlink_metadata(&source_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                stats.not_in_source += 1;
+                continue;
+            }
+            Err(e) => return Err(("getting metadata".to_owned(), source_path, e)),
+        };
+        if target_metadata.is_dir() && source_metadata.is_dir() {
+            rec(
+                source,
+                target,
+                &rel_path,
+                index,
+                settings,
+                compare_content,
+                stats,
+            )?;
+        } else if target_metadata.is_symlink() && source_metadata.is_symlink() {
+            let read_link = |path: &PathBuf| {
+                fs::read_link(path).map_err(|e| {
+                    (
+                        "couldn't read symlink contents".to_owned(),
+                        path.clone(),
+                        e,
+                    )
+                })
+            };
+            let link = read_link(&source_path)?;
+            if link == read_link(&target_path)? {
+                index
+                    .write_symlink(&rel_path, &link)
+                    .map_err(|e| index_err("writing symlink to index", &rel_path, index, e))?;
+                stats.same += 1;
+            } else {
+                stats.different += 1;
+            }
+        } else if target_metadata.is_file() && source_metadata.is_file() {
+            let mut source_file = IndexFile::new_from_metadata(&source_metadata);
+            let target_file = IndexFile::new_from_metadata(&target_metadata);
+            let same_timestamp = settings.ignore_timestamp
+                || source_file.cmp_timestamp(&target_file, settings) == Some(Ordering::Equal);
+            let mut same =
+                source_file.size == target_file.size && (same_timestamp || compare_content);
+            if same && compare_content {
+                let hash = |path: &PathBuf| {
+                    IndexFile::hash_file(path)
+                        .map_err(|e| ("hashing file contents".to_owned(), path.clone(), e))
+                };
+                let source_hash = hash(&source_path)?;
+                same = source_hash == hash(&target_path)?;
+                source_file.hash = Some(source_hash);
+            }
+            if same {
+                if same_timestamp {
+                    source_file.mode = target_file.mode;
+                    source_file.uid = target_file.uid;
+                    source_file.gid = target_file.gid;
+                } else {
+                    // unknown permissions make the next backup update the target's timestamp
+                    source_file.mode = None;
+                    source_file.uid = None;
+                    source_file.gid = None;
+                }
+                index
+                    .write_file(&rel_path, &source_file)
+                    .map_err(|e| index_err("writing file to index", &rel_path, index, e))?;
+                stats.same += 1;
+            } else {
+                stats.different += 1;
+            }
+        } else if let Some(special) = SpecialFile::from_metadata(&source_metadata)
+            && SpecialFile::from_metadata(&target_metadata) == Some(special)
+        {
+            // special files have no contents, so they are the same if their type is
+            let target_file = IndexFile::new_from_metadata(&target_metadata);
+            let source_file = IndexFile {
+                mode: target_file.mode,
+                uid: target_file.uid,
+                gid: target_file.gid,
+                ..IndexFile::new_from_metadata(&source_metadata)
+            };
+            index
+                .write_file(&rel_path, &source_file)
+                .map_err(|e| index_err("writing file to index", &rel_path, index, e))?;
+            stats.same += 1;
+        } else {
+            // different types. add the target's entry to the index,
+            // so that the next backup removes it before copying the source's entry.
+            add_target_entry(index, &rel_path, &target_path, &target_metadata)?;
+            stats.different += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the entry at `target_path` to the index as it is on the target (directories without their contents).
+/// If it doesn't match the source, the next backup replaces it.
+pub fn add_target_entry(
+    index: &mut Index,
+    rel_path: &Path,
+    target_path: &Path,
+    target_metadata: &fs::Metadata,
+) -> Result<(), DiffError> {
+    let result = if target_metadata.is_dir() {
+        index.create_dir(rel_path)
+    } else if target_metadata.is_symlink() {
+        let link = fs::read_link(target_path).map_err(|e| {
+            (
+                "couldn't read symlink contents".to_owned(),
+                target_path.to_owned(),
+                e,
+            )
+        })?;
+        index.write_symlink(rel_path, &link)
+    } else {
+        index.write_file(rel_path, &IndexFile::new_from_metadata(target_metadata))
+    };
+    result.map_err(|e| {
+        (
+            "writing entry to index".to_owned(),
+            index.path().join(rel_path),
+            e,
+        )
+    })
+}
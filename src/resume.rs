@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    delta::{BlockHasher, HashingWriter},
+    index::path_with_suffix,
+    indexfile::IndexFile,
+    repr_file::ReprFile,
+};
+
+/// Large files are copied in chunks of this size, and the progress is saved after each chunk
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+/// How many blocks of a partial copy are compared with the source before the copy is resumed
+const CHECK_BLOCKS: u64 = 8;
+const CHECK_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Where the progress of a resumable copy to the temporary file `tmp` is saved
+pub fn progress_path(tmp: &Path) -> PathBuf {
+    path_with_suffix(tmp, ".progress")
+}
+
+/// Copies `s` to `tmp`, continuing an earlier, interrupted copy if it was made from the same version of `s`.
+/// The progress is saved next to `tmp` after each chunk, and must be removed once `tmp` is no longer needed.
+/// Everything which is copied (or was copied before) is also written to the `hasher`.
+pub fn copy_resumable(
+    s: &Path,
+    tmp: &Path,
+    index_file: &IndexFile,
+    mut hasher: Option<&mut BlockHasher>,
+) -> io::Result<()> {
+    let progress_path = progress_path(tmp);
+    let mut source = fs::File::open(s)?;
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(tmp)?;
+    let start = resume_offset(&source, &file, &progress_path, index_file);
+    if start > 0 {
+        eprintln!(
+            "\n[info] resuming copy of {s:?} after {:.2} GiB",
+            start as f64 / (1024 * 1024 * 1024) as f64
+        );
+    }
+    // anything after `start` may not have been written completely
+    file.set_len(start)?;
+    if let Some(hasher) = &mut hasher {
+        // the part which was already copied was checked to be the same as in the source
+        io::copy(&mut (&mut source).take(start), hasher)?;
+    }
+    source.seek(SeekFrom::Start(start))?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut copied = start;
+    loop {
+        let mut writer = HashingWriter {
+            inner: &mut file,
+            hasher: hasher.as_deref_mut(),
+        };
+        let n = io::copy(&mut (&mut source).take(CHUNK_SIZE), &mut writer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        copied += n;
+        file.sync_data()?;
+        let progress = HashMap::from([
+            ("Size".to_owned(), index_file.size.to_string()),
+            ("Modified".to_owned(), modified(index_file)),
+            ("Copied".to_owned(), copied.to_string()),
+        ]);
+        fs::write(&progress_path, progress.save())?;
+    }
+}
+
+/// How much of the partial copy `file` can be kept, or 0 if the copy has to start from the beginning
+fn resume_offset(
+    source: &fs::File,
+    file: &fs::File,
+    progress_path: &Path,
+    index_file: &IndexFile,
+) -> u64 {
+    let Ok(Ok(progress)) = fs::read_to_string(progress_path)
+        .map(|progress| HashMap::<String, String>::load(&progress))
+    else {
+        return 0;
+    };
+    // the source must be the same version of the file which was being copied
+    if progress.get("Size") != Some(&index_file.size.to_string())
+        || progress.get("Modified") != Some(&modified(index_file))
+        || index_file.last_modified.is_none()
+    {
+        return 0;
+    }
+    let Some(copied) = progress.get("Copied").and_then(|c| c.parse::<u64>().ok()) else {
+        return 0;
+    };
+    if copied > index_file.size || file.metadata().map_or(0, |m| m.len()) < copied {
+        return 0;
+    }
+    // reading the target may be slow, so only a few blocks are compared
+    let block_size = CHECK_BLOCK_SIZE.min(copied);
+    let (mut a, mut b) = (vec![0; block_size as usize], vec![0; block_size as usize]);
+    for i in 0..CHECK_BLOCKS {
+        let offset = (copied - block_size) * i / (CHECK_BLOCKS - 1);
+        let same = source.read_exact_at(&mut a, offset).is_ok()
+            && file.read_exact_at(&mut b, offset).is_ok()
+            && a == b;
+        if !same {
+            return 0;
+        }
+    }
+    copied
+}
+
+fn modified(index_file: &IndexFile) -> String {
+    format!(
+        "{}.{:09}",
+        index_file.last_modified.unwrap_or(0),
+        index_file.last_modified_nanos.unwrap_or(0)
+    )
+}
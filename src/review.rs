@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{indexchanges::IndexChange, update_index::IndexDiff};
+
+const HELP: &str = "\
+  ls [PATH]       show how many changes there are in each entry of PATH
+  exclude PATH    don't apply the changes in PATH during this backup
+  ignore PATH     exclude PATH and add it to the --ignore file, so future backups ignore it, too
+  apply           apply the remaining changes (or press enter)
+  exit            don't apply any changes";
+
+/// Asks the user to confirm the changes, and lets them exclude some of the changes first.
+/// Returns false if the changes should not be applied.
+pub fn review(diff: &mut IndexDiff, ignore_file: Option<&Path>, has_target: bool) -> bool {
+    if has_target {
+        eprintln!(
+            "Type `ls` to look at the changes, `help` to see how to exclude some of them,\nor press enter to apply the changes."
+        );
+    } else {
+        eprintln!(
+            "[warn] You didn't set a `target` directory!\n[warn] Be careful not to update your index without actually applying the changes to the `target` filesystem!\nType 'Ok' and press enter to continue, or `help` to review the changes."
+        );
+    }
+    loop {
+        let line = if let Some(Ok(v)) = io::stdin().lines().next() {
+            v
+        } else {
+            return false;
+        };
+        let line = line.trim();
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = relative_path(arg.trim());
+        match command.to_lowercase().as_str() {
+            "exit" => return false,
+            "" if has_target => return true,
+            "apply" | "ok" => return true,
+            "" => eprintln!("Type 'Ok' and press enter to continue."),
+            "help" => eprintln!("{HELP}"),
+            "ls" => ls(&diff.changes, &arg),
+            "exclude" | "ignore" if arg.as_os_str().is_empty() => {
+                eprintln!("{command} needs a path, like `{command} some/dir`.")
+            }
+            "exclude" => exclude(diff, &arg),
+            "ignore" => {
+                let Some(ignore_file) = ignore_file else {
+                    eprintln!(
+                        "there is no ignore file, use `exclude` or run rembackup with --ignore."
+                    );
+                    continue;
+                };
+                exclude(diff, &arg);
+                match add_to_ignore_file(ignore_file, &arg) {
+                    Ok(()) => eprintln!("[info] added {arg:?} to {ignore_file:?}"),
+                    Err(e) => eprintln!("[warn] couldn't add {arg:?} to {ignore_file:?}: {e}"),
+                }
+            }
+            _ => eprintln!("unknown command {command:?}, type `help` to see all commands."),
+        }
+    }
+}
+
+/// Paths are relative to the source, so `./` and `/` at the start are removed
+fn relative_path(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+fn ls(changes: &[IndexChange], dir: &Path) {
+    // name -> (changes, size of files to copy, is a directory)
+    let mut entries = HashMap::<OsString, (usize, u64, bool)>::new();
+    for change in changes {
+        for path in change.paths() {
+            let Ok(rel_path) = path.strip_prefix(dir) else {
+                continue;
+            };
+            let mut components = rel_path.components();
+            let Some(name) = components.next() else {
+                continue;
+            };
+            let entry = entries.entry(name.as_os_str().to_owned()).or_default();
+            entry.0 += 1;
+            entry.1 += change.transfer_size();
+            entry.2 |= components.next().is_some() || change.is_dir();
+        }
+    }
+    if entries.is_empty() {
+        eprintln!("there are no changes in {dir:?}");
+        return;
+    }
+    let mut entries = entries.into_iter().collect::<Vec<_>>();
+    // largest first, like the changes
+    entries.sort_by(|(a_name, a), (b_name, b)| {
+        b.1.cmp(&a.1).then(b.0.cmp(&a.0)).then(a_name.cmp(b_name))
+    });
+    for (name, (count, size, is_dir)) in entries {
+        eprintln!(
+            " {count:>8} changes {:>10.3} GiB  {}{}",
+            size as f64 / (1024 * 1024 * 1024) as f64,
+            dir.join(name).display(),
+            if is_dir { "/" } else { "" }
+        );
+    }
+}
+
+/// Removes all changes to `path` and everything in it
+fn exclude(diff: &mut IndexDiff, path: &Path) {
+    let changes_before = diff.changes.len();
+    let mut excluded_size = 0;
+    diff.changes.retain(|change| {
+        if !change.paths().iter().any(|p| p.starts_with(path)) {
+            return true;
+        }
+        excluded_size += change.transfer_size();
+        false
+    });
+    let excluded = changes_before - diff.changes.len();
+    if excluded == 0 {
+        eprintln!("there are no changes in {path:?}");
+        return;
+    }
+    diff.total_size = diff.total_size.saturating_sub(excluded_size);
+    for change in &mut diff.changes {
+        if let IndexChange::AddDir(dir, _, size) = change
+            && path.starts_with(dir)
+        {
+            *size = size.saturating_sub(excluded_size);
+        }
+    }
+    eprintln!(
+        "[info] excluded {excluded} changes, {} changes are left.",
+        diff.changes.len()
+    );
+}
+
+/// Appends a line which ignores `path` to the ignore file
+fn add_to_ignore_file(ignore_file: &Path, path: &Path) -> io::Result<()> {
+    let path = path
+        .to_str()
+        .filter(|path| !path.contains(['\n', '\r']))
+        .ok_or_else(|| io::Error::other("the path can't be written to the ignore file"))?;
+    let needs_newline = match fs::read(ignore_file) {
+        Ok(contents) => !contents.is_empty() && !contents.ends_with(b"\n"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(ignore_file)?;
+    if needs_newline {
+        writeln!(file)?;
+    }
+    writeln!(file, "*= {path}")
+}
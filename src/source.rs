@@ -0,0 +1,119 @@
+use std::{
+    ffi::OsStr,
+    fmt::{self, Display},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::index;
+
+/// The data which is backed up: either a single directory,
+/// or several named directories which are backed up to sub-directories of the index and target.
+#[derive(Clone, Debug)]
+pub enum Source {
+    Dir(PathBuf),
+    /// (name, path), sorted by name.
+    /// The root of the index and target contains one directory for each of them.
+    Named(Vec<(String, PathBuf)>),
+}
+
+impl Source {
+    /// The path in source of the entry at `rel_path` in the index and target.
+    /// `None` for the root of named sources, which doesn't exist in source, and for unknown names.
+    pub fn path(&self, rel_path: &Path) -> Option<PathBuf> {
+        match self {
+            Self::Dir(dir) => Some(dir.join(rel_path)),
+            Self::Named(sources) => {
+                let mut components = rel_path.components();
+                let name = components.next()?;
+                let (_, path) = sources
+                    .iter()
+                    .find(|(n, _)| OsStr::new(n) == name.as_os_str())?;
+                Some(path.join(components.as_path()))
+            }
+        }
+    }
+    /// The directories which are backed up, and where they are in the index and target
+    pub fn roots(&self) -> Vec<(&Path, &Path)> {
+        match self {
+            Self::Dir(dir) => vec![(Path::new(""), dir.as_path())],
+            Self::Named(sources) => sources
+                .iter()
+                .map(|(name, path)| (Path::new(name.as_str()), path.as_path()))
+                .collect(),
+        }
+    }
+    /// Makes all paths absolute, using `cwd` for relative paths
+    pub fn absolute(self, cwd: Option<&Path>) -> Self {
+        let absolute = |path: PathBuf| {
+            if path.is_absolute() {
+                path
+            } else {
+                cwd.expect("tried to use a relative path when there is no valid CWD")
+                    .join(path)
+            }
+        };
+        match self {
+            Self::Dir(dir) => Self::Dir(absolute(dir)),
+            Self::Named(sources) => Self::Named(
+                sources
+                    .into_iter()
+                    .map(|(name, path)| (name, absolute(path)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dir(dir) => write!(f, "{dir:?}"),
+            Self::Named(sources) => {
+                for (i, (name, path)) in sources.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}={path:?}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parses `NAME=PATH`
+pub fn parse_named_source(text: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = text
+        .split_once('=')
+        .ok_or_else(|| format!("{text:?} should look like NAME=PATH"))?;
+    check_name(name)?;
+    if path.is_empty() {
+        return Err(format!("the path of source {name:?} is empty"));
+    }
+    Ok((name.to_owned(), PathBuf::from(path)))
+}
+/// Names are used as directories in the index and target, so they must be a single path component
+pub fn check_name(name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    if !matches!(components.next(), Some(Component::Normal(_)))
+        || components.next().is_some()
+        || name.contains(['/', '\n', '\r', '\t'])
+    {
+        return Err(format!("{name:?} can't be used as the name of a source"));
+    }
+    if index::is_reserved(Path::new(name)) {
+        return Err(format!(
+            "{name:?} can't be used as the name of a source, because names starting with {:?} are reserved",
+            index::RESERVED_PREFIX
+        ));
+    }
+    Ok(())
+}
+/// Sorts the named sources and checks that there are no duplicate names
+pub fn named_sources(mut sources: Vec<(String, PathBuf)>) -> Result<Source, String> {
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(pair) = sources.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(format!("there are multiple sources named {:?}", pair[0].0));
+    }
+    Ok(Source::Named(sources))
+}
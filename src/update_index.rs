@@ -1,15 +1,28 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
     fs, io,
     path::{Path, PathBuf},
+    os::unix::fs::MetadataExt,
+    sync::{
+        Mutex,
+        atomic::{self, AtomicUsize},
+    },
+    thread,
+    time::Duration,
 };
 
 use clap::Args;
 
 use crate::{
+    args::{OutputFormat, parse_duration},
     config::{FsEntry, Ignore, Match, Specifier},
+    delta::BlockHashes,
+    index::{self, Index, IndexEntry, IndexFormat},
     indexchanges::IndexChange,
-    indexfile::IndexFile,
+    indexfile::{IndexFile, SpecialFile},
+    source::Source,
 };
 
 #[derive(Clone, Default, Args)]
@@ -17,17 +30,18 @@ pub struct Settings {
     /// don't sort the changes that form the backup
     ///
     /// disables sort options.
-    /// symlinks will still be created last.
     #[arg(long)]
     pub dont_sort: bool,
     /// start with smaller directories rather than larger ones
-    ///
-    /// symlinks will still be created last.
     #[arg(long)]
     pub smallest_first: bool,
     /// show changes in the order in which they will be applied, not reversed
     #[arg(long)]
     pub dont_reverse_output: bool,
+    /// how changes are shown. `json` prints one JSON object per line to stdout,
+    /// one for each change (in the order in which they will be applied) and a summary at the end.
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: OutputFormat,
 
     /// don't update files just because their timestamp is different
     #[arg(long)]
@@ -44,248 +58,851 @@ pub struct Settings {
     /// don't replace files if their timestamp is known in source but unknown in index
     #[arg(long)]
     pub dont_replace_if_timestamp_found: bool,
+    /// treat timestamps as equal if they differ by less than this, like `2s` for FAT filesystems.
+    ///
+    /// supported units are ns, us, ms, s, m, h and d.
+    #[arg(long, value_parser = parse_duration, default_value = "0s")]
+    pub timestamp_granularity: Duration,
+
+    /// compare the contents of files instead of their timestamps
+    ///
+    /// reads every file in source to compute its hash, which is stored in the index.
+    /// files whose hash didn't change will not be copied again, even if their timestamp did.
+    /// files which don't have a hash in the index yet are compared using their timestamps, and their hash is saved.
+    #[arg(long)]
+    pub checksum: bool,
+
+    /// how many threads are used to read directories while looking for changes.
+    /// this helps with fast SSDs and network filesystems.
+    #[arg(long, default_value_t = 8)]
+    pub diff_threads: usize,
+
+    /// don't back up directories which are on a different filesystem than <source>, like mounted drives.
+    /// they are listed in the summary, and treated as if they were ignored.
+    #[arg(long, short = 'x')]
+    pub one_file_system: bool,
+    /// don't back up fifos, sockets and device nodes. they are counted in the summary.
+    #[arg(long)]
+    pub skip_special: bool,
+    /// copy files and directories which were moved or renamed in <source> again,
+    /// instead of moving them on <target>
+    #[arg(long)]
+    pub dont_detect_moves: bool,
+    /// if a file or directory in <source> can't be read, continue with the rest of the backup.
+    /// the entries which couldn't be read are left untouched in the backup and listed at the end.
+    #[arg(long)]
+    pub keep_going: bool,
+}
+
+/// what we were trying to do, the path we were doing it on, and the error that happened
+pub type DiffError = (String, PathBuf, io::Error);
+
+pub struct IndexDiff {
+    /// the total size of all files which will be copied
+    pub total_size: u64,
+    pub changes: Vec<IndexChange>,
+    /// directories which weren't backed up because they are on a different filesystem than `source`
+    pub skipped_mounts: Vec<PathBuf>,
+    /// how many special files weren't backed up because of `--skip-special`
+    pub skipped_special: usize,
+    /// errors which were ignored because of `--keep-going`, sorted by path.
+    /// there are no changes for these paths (or anything inside them).
+    pub errors: Vec<DiffError>,
 }
 
-pub fn perform_index_diff<'a>(
-    source: &Path,
-    index: &'a Path,
-    target: Option<&'a Path>,
+pub fn perform_index_diff(
+    source: &Source,
+    index: &Index,
+    target: Option<&Path>,
     mut ignore: Ignore,
     settings: &Settings,
     sort_by_size_largest: Option<bool>,
-) -> Result<(u64, Vec<IndexChange>), (String, PathBuf, io::Error)> {
-    if let Ok(inner_index) = index.strip_prefix(source) {
-        eprintln!("[info] source contains index at {inner_index:?}, but index will not be part of the backup.");
-        ignore.0.push(Specifier::InDir {
-            dir: Match::Eq(inner_index.to_owned()),
-            inner: Ignore(vec![]),
-        });
-    }
-    if let Some(target) = target {
-        if let Ok(inner_target) = target.strip_prefix(source) {
+) -> Result<IndexDiff, DiffError> {
+    exclude_index_and_target(source, index, target, &mut ignore);
+    diff_dirs(source, index, &ignore, settings, sort_by_size_largest, None)
+}
+
+/// Adds the index and target to `ignore` if they are inside of `source`
+pub fn exclude_index_and_target(
+    source: &Source,
+    index: &Index,
+    target: Option<&Path>,
+    ignore: &mut Ignore,
+) {
+    for (name, source) in source.roots() {
+        if let Ok(inner_index) = index.path().strip_prefix(source) {
+            let inner_index = name.join(inner_index);
+            eprintln!("[info] source contains index at {inner_index:?}, but index will not be part of the backup.");
+            ignore.0.push(match index.format() {
+                IndexFormat::Tree => Specifier::InDir {
+                    dir: Match::Eq(inner_index),
+                    inner: Ignore(vec![]),
+                },
+                IndexFormat::Packed => Specifier::Entries(Match::Eq(inner_index)),
+            });
+            for sidecar in index.sidecar_paths() {
+                if let Ok(inner_sidecar) = sidecar.strip_prefix(source) {
+                    ignore
+                        .0
+                        .push(Specifier::Entries(Match::Eq(name.join(inner_sidecar))));
+                }
+            }
+        }
+        if let Some(target) = target
+            && let Ok(inner_target) = target.strip_prefix(source)
+        {
+            let inner_target = name.join(inner_target);
             eprintln!("[info] source contains target at {inner_target:?}, but target will not be part of the backup.");
             ignore.0.push(Specifier::InDir {
-                dir: Match::Eq(inner_target.to_owned()),
+                dir: Match::Eq(inner_target),
                 inner: Ignore(vec![]),
             });
         }
     }
-    if let Some((total_size, changes)) = rec(
-        source.as_ref(),
-        Path::new(""),
+}
+
+/// Like `perform_index_diff`, but `ignore` must already exclude the index and target (see `exclude_index_and_target`).
+///
+/// If `dirs` is set, only the entries directly inside of these directories (relative to `source`) are compared.
+/// Their subdirectories are only compared if they are new or were moved.
+pub fn diff_dirs(
+    source: &Source,
+    index: &Index,
+    ignore: &Ignore,
+    settings: &Settings,
+    sort_by_size_largest: Option<bool>,
+    dirs: Option<&[PathBuf]>,
+) -> Result<IndexDiff, DiffError> {
+    let mut walker = Walker {
+        source,
         index,
-        &ignore,
+        ignore,
         settings,
         sort_by_size_largest,
-    )? {
-        Ok((total_size, changes))
-    } else {
-        Ok((0, vec![]))
+        free_threads: AtomicUsize::new(settings.diff_threads.saturating_sub(1)),
+        shallow: dirs.is_some(),
+        roots: match dirs {
+            None => vec![PathBuf::new()],
+            Some(dirs) => existing_dirs(source, index, dirs).into_iter().collect(),
+        },
+        skipped_mounts: Mutex::new(vec![]),
+        skipped_special: AtomicUsize::new(0),
+        moved_dirs: HashMap::new(),
+        errors: Mutex::new(vec![]),
+    };
+    let mut total_size = 0;
+    let mut changes = vec![];
+    for dir in &walker.roots {
+        // the root of named sources doesn't exist in source
+        let metadata = match source.path(dir).map(|path| (fs::metadata(&path), path)) {
+            None => None,
+            Some((Ok(metadata), _)) => Some(metadata),
+            Some((Err(e), path)) => {
+                walker.error(("getting metadata".to_owned(), path, e))?;
+                continue;
+            }
+        };
+        if let Some((dir_size, dir_changes)) =
+            thread::scope(|scope| walker.rec(scope, dir, metadata.as_ref()))?
+        {
+            total_size += dir_size;
+            changes.extend(dir_changes);
+        }
+    }
+    if !settings.dont_detect_moves {
+        detect_moves(&mut walker, &mut total_size, &mut changes)?;
     }
+    let mut skipped_mounts = walker
+        .skipped_mounts
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    skipped_mounts.sort_unstable();
+    let mut errors = walker
+        .errors
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    errors.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+    Ok(IndexDiff {
+        total_size,
+        changes,
+        skipped_mounts,
+        skipped_special: walker.skipped_special.into_inner(),
+        errors,
+    })
 }
-fn rec(
+
+/// Everything `rec` needs which doesn't change between directories
+struct Walker<'a> {
     // location of source files
-    source: &Path,
-    // relative path used on this iteration
-    rel_path: &Path,
-    // location of the index
-    index_files: &Path,
-    ignore: &Ignore,
-    settings: &Settings,
+    source: &'a Source,
+    index: &'a Index,
+    ignore: &'a Ignore,
+    settings: &'a Settings,
     sort_by_size_largest: Option<bool>,
-) -> Result<Option<(u64, Vec<IndexChange>)>, (String, PathBuf, io::Error)> {
-    let mut removals = vec![];
-    let mut ichanges = vec![];
-    let mut total_size = 0;
-    // used to find removals
-    let index_rel_path = index_files.join(rel_path);
-    let (mut index_entries, dir_is_new) = match fs::read_dir(&index_rel_path) {
-        Err(_) => (HashMap::new(), true),
-        Ok(e) => (
-            e.into_iter()
-                .filter_map(|v| v.ok())
-                .map(|v| {
-                    Ok((
-                        v.file_name(),
-                        v.file_type()
-                            .map_err(|e| ("getting file type".to_owned(), v.path(), e))?
-                            .is_dir(),
-                    ))
-                })
-                .collect::<Result<_, (String, PathBuf, io::Error)>>()?,
-            false,
-        ),
-    };
-    // compare source files with index
-    let source_files_path = source.join(rel_path);
-    let source_files = fs::read_dir(&source_files_path)
-        .map_err(|e| ("getting entries".to_owned(), source_files_path.clone(), e))?
-        .collect::<Vec<_>>();
-    // find changes/adds
-    for entry in source_files {
-        let entry = entry.map_err(|e| {
-            (
-                "error with an entry within this directory".to_owned(),
-                source_files_path.clone(),
-                e,
-            )
-        })?;
-        let entry_path = entry.path();
-        let rel_path = rel_path.join(entry.file_name());
-        let metadata = fs::symlink_metadata(&entry_path);
+    /// how many more threads may be started.
+    /// if none are available, subdirectories are handled by the current thread.
+    free_threads: AtomicUsize,
+    /// don't compare the contents of subdirectories which are already in the index
+    shallow: bool,
+    /// the directories which are compared, each with its own `IndexChange::AddDir`
+    roots: Vec<PathBuf>,
+    /// directories on other filesystems, if `settings.one_file_system` is set
+    skipped_mounts: Mutex<Vec<PathBuf>>,
+    /// special files which were skipped, if `settings.skip_special` is set
+    skipped_special: AtomicUsize,
+    /// directories which were moved in source (new path -> old path).
+    /// when comparing their contents, the index is read at the old path.
+    moved_dirs: HashMap<PathBuf, PathBuf>,
+    /// errors which were ignored, if `settings.keep_going` is set
+    errors: Mutex<Vec<DiffError>>,
+}
 
-        // ignore entries
-        let fs_entry = FsEntry {
-            path: &rel_path,
-            is_directory: metadata.as_ref().ok().map(|v| v.is_dir()),
-        };
-        if ignore.matches_or_default(&fs_entry) {
-            continue;
-        }
+type RecResult = Result<Option<(u64, Vec<IndexChange>)>, DiffError>;
 
-        let metadata = metadata.map_err(|e| ("getting metadata (you have to ignore this using a * pattern instead of + or /, because we don't know if it's a directory or not)".to_owned(), entry_path.clone(), e))?;
-        let in_index_and_is_dir = index_entries.remove(&entry.file_name());
-        if metadata.is_dir() {
-            if let Some(false) = in_index_and_is_dir {
-                // is dir, but was file -> remove file
-                removals.push(IndexChange::RemoveFile(rel_path.clone()));
+/// The changes for one entry of a directory, in the same order as the entries
+enum Pending<'scope> {
+    Done(u64, Vec<IndexChange>),
+    Thread(thread::ScopedJoinHandle<'scope, RecResult>),
+}
+
+impl Walker<'_> {
+    fn rec<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        // relative path used on this iteration
+        rel_path: &Path,
+        // metadata of the directory at `rel_path`, `None` for the root of named sources
+        dir_metadata: Option<&fs::Metadata>,
+    ) -> RecResult {
+        let Self {
+            source,
+            index,
+            ignore,
+            settings,
+            sort_by_size_largest,
+            ..
+        } = *self;
+        let mut removals = vec![];
+        let mut pending = vec![];
+        let mut total_size = 0;
+        // used to find removals
+        let index_rel_path = self.index_path(rel_path);
+        let (mut index_entries, dir_is_new) = match index.read_dir(&index_rel_path) {
+            Ok(Some(entries)) => (entries, false),
+            Ok(None) => (HashMap::new(), true),
+            Err(e) => {
+                self.error((
+                    "reading directory in index".to_owned(),
+                    index.path().join(&index_rel_path),
+                    e,
+                ))?;
+                return Ok(None);
             }
-            if let Some((rec_size, rec_changes)) = rec(
-                source,
-                &rel_path,
-                index_files,
-                ignore,
-                settings,
-                sort_by_size_largest,
-            )? {
-                total_size += rec_size;
-                ichanges.push((rec_size, rec_changes));
+        };
+        // compare source files with index
+        let named_root = dir_metadata.is_none();
+        let source_files = match source.path(rel_path) {
+            Some(source_files_path) => fs::read_dir(&source_files_path)
+                .map_err(|e| ("getting entries".to_owned(), source_files_path.clone(), e))
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| (entry.file_name(), entry.path())))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| {
+                            (
+                                "error with an entry within this directory".to_owned(),
+                                source_files_path.clone(),
+                                e,
+                            )
+                        })
+                }),
+            // the root of named sources contains the sources
+            None => Ok(source
+                .roots()
+                .into_iter()
+                .map(|(name, path)| (name.as_os_str().to_owned(), path.to_owned()))
+                .collect()),
+        };
+        // if some entries can't be read, we can't know which ones were removed,
+        // so the entire directory is left untouched
+        let mut source_files = match source_files {
+            Ok(source_files) => source_files,
+            Err(e) => {
+                self.error(e)?;
+                return Ok(None);
             }
-        } else {
-            if let Some(true) = in_index_and_is_dir {
-                // is file, but was dir -> remove dir
-                removals.push(IndexChange::RemoveDir(rel_path.clone()));
+        };
+        // the order of entries must not depend on the filesystem or on threads
+        source_files.sort_unstable();
+        // find changes/adds
+        for (name, entry_path) in source_files {
+            let rel_path = rel_path.join(&name);
+            if index::is_reserved(&rel_path) {
+                eprintln!(
+                    "[warn] {entry_path:?} will not be backed up, because names starting with {:?} are reserved for rembackup.",
+                    index::RESERVED_PREFIX
+                );
+                continue;
             }
-            let index_file_path = index_files.join(&rel_path);
-            let new_is_symlink = metadata.is_symlink();
-            let old_is_symlink = index_file_path
-                .symlink_metadata()
-                .is_ok_and(|meta| meta.is_symlink());
-            if new_is_symlink && old_is_symlink {
-                // cd to file's parent directory, in case of relative links, just to be sure
-                let cwd = std::env::current_dir()
-                    .map_err(|e| (format!("couldn't get CWD"), entry_path.clone(), e))?;
-                std::env::set_current_dir(&source_files_path).map_err(|e| {
-                    (
-                        format!("could not set CWD to {}", source_files_path.display()),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                let new_link = fs::read_link(&entry_path).map_err(|e| {
-                    (
-                        format!("couldn't read symlink contents"),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                std::env::set_current_dir(&index_rel_path).map_err(|e| {
-                    (
-                        format!("could not set CWD to {}", index_rel_path.display()),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                let old_link = fs::read_link(&index_file_path).map_err(|e| {
-                    (
-                        format!("couldn't read indexfile symlink contents"),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                std::env::set_current_dir(&cwd).map_err(|e| {
-                    (
-                        format!("could not reset CWD to {}", cwd.display()),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                if new_link != old_link {
-                    ichanges.push((0, vec![IndexChange::AddSymlink(rel_path, new_link)]));
+            let metadata = if named_root {
+                fs::metadata(&entry_path)
+            } else {
+                fs::symlink_metadata(&entry_path)
+            };
+
+            // ignore entries
+            let fs_entry = FsEntry {
+                path: &rel_path,
+                is_directory: metadata.as_ref().ok().map(|v| v.is_dir()),
+            };
+            if ignore.matches_or_default(&fs_entry) {
+                continue;
+            }
+
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    self.error(("getting metadata (you have to ignore this using a * pattern instead of + or /, because we don't know if it's a directory or not)".to_owned(), entry_path.clone(), e))?;
+                    // don't remove it from the backup
+                    index_entries.remove(&name);
+                    continue;
                 }
-            } else if new_is_symlink {
-                let cwd = std::env::current_dir()
-                    .map_err(|e| (format!("couldn't get CWD"), entry_path.clone(), e))?;
-                std::env::set_current_dir(&source_files_path).map_err(|e| {
-                    (
-                        format!("could not set CWD to {}", source_files_path.display()),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                let new_link = fs::read_link(&entry_path).map_err(|e| {
-                    (
-                        format!("couldn't read symlink contents"),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
-                std::env::set_current_dir(&cwd).map_err(|e| {
-                    (
-                        format!("could not reset CWD to {}", cwd.display()),
-                        entry_path.clone(),
-                        e,
-                    )
-                })?;
+            };
+            // directories on other filesystems are skipped, so `dir_metadata` is on the same filesystem as source
+            if settings.one_file_system
+                && metadata.is_dir()
+                && dir_metadata.is_some_and(|dir_metadata| metadata.dev() != dir_metadata.dev())
+            {
+                // like an ignored directory, so it will be removed from the backup if it was in it
+                self.skipped_mounts
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(rel_path);
+                continue;
+            }
+            if settings.skip_special && SpecialFile::from_metadata(&metadata).is_some() {
+                self.skipped_special.fetch_add(1, atomic::Ordering::Relaxed);
+                continue;
+            }
+            let in_index_and_is_dir = index_entries.remove(&name);
+            if metadata.is_dir() {
                 if let Some(false) = in_index_and_is_dir {
-                    // was file before
+                    // is dir, but was file -> remove file
                     removals.push(IndexChange::RemoveFile(rel_path.clone()));
                 }
-                ichanges.push((0, vec![IndexChange::AddSymlink(rel_path, new_link)]));
+                if self.shallow && in_index_and_is_dir == Some(true) {
+                    continue;
+                }
+                if self.take_thread() {
+                    pending.push(Pending::Thread(scope.spawn(move || {
+                        let result = self.rec(scope, &rel_path, Some(&metadata));
+                        self.free_threads.fetch_add(1, atomic::Ordering::Relaxed);
+                        result
+                    })));
+                } else if let Some((rec_size, rec_changes)) =
+                    self.rec(scope, &rel_path, Some(&metadata))?
+                {
+                    total_size += rec_size;
+                    pending.push(Pending::Done(rec_size, rec_changes));
+                }
             } else {
-                let newif = IndexFile::new_from_metadata(&metadata);
-                let oldif = IndexFile::from_path(&index_file_path);
-                if old_is_symlink {
-                    removals.push(IndexChange::RemoveFile(rel_path.clone()));
+                if let Some(true) = in_index_and_is_dir {
+                    // is file, but was dir -> remove dir
+                    removals.push(IndexChange::RemoveDir(rel_path.clone()));
+                }
+                // if this can't be read from the index, the file will just be copied again
+                let old_entry = index.get(&self.index_path(&rel_path)).ok().flatten();
+                let new_is_symlink = metadata.is_symlink();
+                let old_is_symlink = matches!(old_entry, Some(IndexEntry::Symlink(_)));
+                if new_is_symlink {
+                    // the link is stored as it is, relative links are not resolved
+                    let new_link = match fs::read_link(&entry_path) {
+                        Ok(link) => link,
+                        Err(e) => {
+                            self.error((
+                                "couldn't read symlink contents".to_owned(),
+                                entry_path.clone(),
+                                e,
+                            ))?;
+                            // don't remove the directory which was here before
+                            removals.retain(
+                                |c| !matches!(c, IndexChange::RemoveDir(p) if *p == rel_path),
+                            );
+                            continue;
+                        }
+                    };
+                    if let Some(IndexEntry::Symlink(old_link)) = &old_entry {
+                        if &new_link != old_link {
+                            pending.push(Pending::Done(
+                                0,
+                                vec![IndexChange::AddSymlink(rel_path, new_link)],
+                            ));
+                        }
+                    } else {
+                        if let Some(false) = in_index_and_is_dir {
+                            // was file before
+                            removals.push(IndexChange::RemoveFile(rel_path.clone()));
+                        }
+                        pending.push(Pending::Done(
+                            0,
+                            vec![IndexChange::AddSymlink(rel_path, new_link)],
+                        ));
+                    }
+                } else {
+                    let mut newif = IndexFile::new_from_metadata(&metadata);
+                    // special files have no contents, and opening a fifo would block
+                    if settings.checksum && newif.special.is_none() {
+                        match IndexFile::hash_file(&entry_path) {
+                            Ok(hash) => newif.hash = Some(hash),
+                            Err(e) => {
+                                self.error((
+                                    "hashing file contents".to_owned(),
+                                    entry_path.clone(),
+                                    e,
+                                ))?;
+                                // don't remove the directory which was here before
+                                removals.retain(
+                                    |c| !matches!(c, IndexChange::RemoveDir(p) if *p == rel_path),
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    if old_is_symlink {
+                        removals.push(IndexChange::RemoveFile(rel_path.clone()));
+                    }
+                    match old_entry {
+                        Some(IndexEntry::File(oldif))
+                            if !newif.should_be_updated(&oldif, settings) =>
+                        {
+                            // with --checksum, files without a hash in the index get one,
+                            // so changes which keep the size and timestamp are found next time
+                            let record_hash = newif.hash.is_some() && oldif.hash.is_none();
+                            if record_hash
+                                || newif.metadata_changed(&oldif, newif.special.is_some(), settings)
+                            {
+                                if newif.hash.is_none() {
+                                    newif.hash = oldif.hash;
+                                }
+                                newif.blocks = oldif.blocks;
+                                pending.push(Pending::Done(
+                                    0,
+                                    vec![IndexChange::UpdateMetadata(rel_path, false, newif)],
+                                ));
+                            }
+                        }
+                        Some(IndexEntry::File(oldif))
+                            if newif.special.is_none()
+                                && oldif.blocks.as_ref().is_some_and(|b| b.usable()) =>
+                        {
+                            // reads the file to find out how much of it changed
+                            let blocks = match BlockHashes::hash_file(&entry_path) {
+                                Ok(blocks) => blocks,
+                                Err(e) => {
+                                    self.error((
+                                        "hashing file blocks".to_owned(),
+                                        entry_path.clone(),
+                                        e,
+                                    ))?;
+                                    continue;
+                                }
+                            };
+                            let old_blocks = oldif.blocks.as_ref().expect("checked above");
+                            let size = blocks.delta_size(newif.size, old_blocks);
+                            total_size += size;
+                            pending.push(Pending::Done(
+                                size,
+                                vec![IndexChange::UpdateFile(rel_path, newif, oldif, size)],
+                            ));
+                        }
+                        _ if newif.special.is_some() => {
+                            pending.push(Pending::Done(
+                                0,
+                                vec![IndexChange::AddSpecial(rel_path, newif)],
+                            ));
+                        }
+                        _ => {
+                            total_size += newif.size;
+                            pending.push(Pending::Done(
+                                newif.size,
+                                vec![IndexChange::AddFile(rel_path, newif)],
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        // wait for subdirectories which are handled by other threads
+        let mut ichanges = Vec::with_capacity(pending.len());
+        for pending in pending {
+            match pending {
+                Pending::Done(size, changes) => ichanges.push((size, changes)),
+                Pending::Thread(handle) => {
+                    let result = handle
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e));
+                    if let Some((rec_size, rec_changes)) = result? {
+                        total_size += rec_size;
+                        ichanges.push((rec_size, rec_changes));
+                    }
                 }
-                match oldif {
-                    Ok(Ok(oldif)) if !newif.should_be_updated(&oldif, settings) => {}
-                    _ => {
-                        total_size += newif.size;
-                        ichanges.push((newif.size, vec![IndexChange::AddFile(rel_path, newif)]));
+            }
+        }
+        // removals
+        let mut index_entries = index_entries.into_iter().collect::<Vec<_>>();
+        index_entries.sort_unstable();
+        for (removed_file, is_dir) in index_entries {
+            if named_root {
+                eprintln!(
+                    "[info] {removed_file:?} is in the index, but isn't one of the sources. It will not be changed."
+                );
+                continue;
+            }
+            removals.push(if is_dir {
+                IndexChange::RemoveDir(rel_path.join(removed_file))
+            } else {
+                IndexChange::RemoveFile(rel_path.join(removed_file))
+            });
+        }
+        // sorting (stable, so entries of the same size stay sorted by name)
+        if let Some(sort_largest_first) = sort_by_size_largest {
+            if sort_largest_first {
+                ichanges.sort_by_key(|v| std::cmp::Reverse(v.0));
+            } else {
+                ichanges.sort_by_key(|v| v.0);
+            }
+        }
+        // directory metadata, which is updated after the contents because they change its modification time
+        let dir_meta = dir_metadata
+            .map(|dir_metadata| IndexFile {
+                size: 0,
+                ..IndexFile::new_from_metadata(dir_metadata)
+            })
+            .filter(|dir_meta| match index.dir_metadata(&index_rel_path) {
+                Ok(Some(old)) => dir_meta.metadata_changed(&old, true, settings),
+                Ok(None) | Err(_) => true,
+            });
+        // combine everything
+        if !dir_is_new && removals.is_empty() && ichanges.is_empty() && dir_meta.is_none() {
+            return Ok(None);
+        }
+        let dir_meta_change = dir_meta
+            .map(|dir_meta| IndexChange::UpdateMetadata(rel_path.to_path_buf(), true, dir_meta));
+        let changes = [IndexChange::AddDir(
+            rel_path.to_path_buf(),
+            dir_is_new,
+            total_size,
+        )]
+        .into_iter()
+        .chain(removals)
+        .chain(ichanges.into_iter().flat_map(|(_, v)| v))
+        .chain(dir_meta_change)
+        .collect();
+        Ok(Some((total_size, changes)))
+    }
+
+    /// The path of an entry in the index, which is different from `rel_path` if it was moved
+    fn index_path<'p>(&self, rel_path: &'p Path) -> Cow<'p, Path> {
+        for (new_path, old_path) in &self.moved_dirs {
+            if let Ok(inner) = rel_path.strip_prefix(new_path) {
+                return Cow::Owned(old_path.join(inner));
+            }
+        }
+        Cow::Borrowed(rel_path)
+    }
+
+    /// Returns the error, or, if `settings.keep_going` is set, remembers it so the caller can skip the entry
+    fn error(&self, e: DiffError) -> Result<(), DiffError> {
+        if !self.settings.keep_going {
+            return Err(e);
+        }
+        self.errors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(e);
+        Ok(())
+    }
+
+    /// The root which contains `rel_path`
+    fn root_of(&self, rel_path: &Path) -> &Path {
+        self.roots
+            .iter()
+            .filter(|root| rel_path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .map_or(Path::new(""), |root| root)
+    }
+
+    /// Reserves a thread if one is available
+    fn take_thread(&self) -> bool {
+        self.free_threads
+            .fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |n| {
+                n.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// A file which will be removed from the index, but may have been moved somewhere else in source
+struct RemovedFile {
+    path: PathBuf,
+    file: IndexFile,
+    /// the directory containing the removed entry (or the removed directory containing it),
+    /// which still exists in source
+    existing_dir: PathBuf,
+    /// true if there is a `RemoveFile` change for this path,
+    /// false if it is removed as part of a directory
+    direct: bool,
+}
+
+/// Replaces the additions of files and directories which were moved in source with `IndexChange::Move`s.
+/// Entries are moved if their inode is the same as the inode of a removed entry,
+/// and, for files, if their size and modification time are also the same.
+/// Moved directories are compared with their old location in the index, so changes in them are still found.
+fn detect_moves(
+    walker: &mut Walker,
+    total_size: &mut u64,
+    changes: &mut Vec<IndexChange>,
+) -> Result<(), DiffError> {
+    let Walker {
+        source,
+        index,
+        settings,
+        ..
+    } = *walker;
+    // directories
+    let mut removed_dirs = HashMap::new();
+    for change in changes.iter() {
+        if let IndexChange::RemoveDir(path) = change
+            && let Ok(Some(IndexFile {
+                inode: Some(inode), ..
+            })) = index.dir_metadata(path)
+        {
+            removed_dirs.insert(inode, path.clone());
+        }
+    }
+    // (old path, new path, metadata)
+    let mut dir_moves: Vec<(PathBuf, PathBuf, fs::Metadata)> = vec![];
+    if !removed_dirs.is_empty() {
+        for change in changes.iter() {
+            if let IndexChange::AddDir(path, true, _) = change
+                // the contents of moved directories are compared later
+                && !dir_moves.iter().any(|(_, moved, _)| path.starts_with(moved))
+                && let Some(Ok(metadata)) = source.path(path).map(fs::symlink_metadata)
+                && let Some(old_path) = removed_dirs.get(&metadata.ino())
+                && same_filesystem(source, parent(old_path), &metadata)
+            {
+                let old_path = removed_dirs
+                    .remove(&metadata.ino())
+                    .expect("just got this entry");
+                dir_moves.push((old_path, path.clone(), metadata));
+            }
+        }
+    }
+    for (old_path, new_path, _) in &dir_moves {
+        walker.moved_dirs.insert(new_path.clone(), old_path.clone());
+    }
+    // changes in a moved directory's subdirectories may have been reported for its old path
+    walker.shallow = false;
+    for (old_path, new_path, metadata) in &dir_moves {
+        // replace the changes which add the directory's contents with the changes to its old contents
+        let start = changes
+            .iter()
+            .position(|c| matches!(c, IndexChange::AddDir(p, true, _) if p == new_path))
+            .expect("the moved directory was added");
+        let end = start
+            + changes[start..]
+                .iter()
+                .position(|c| matches!(c, IndexChange::UpdateMetadata(p, true, _) if p == new_path))
+                .expect("the metadata of new directories is always updated");
+        let IndexChange::AddDir(_, _, old_size) = changes[start] else {
+            unreachable!("found AddDir at this position");
+        };
+        let walker = &*walker;
+        let (new_size, new_changes) =
+            thread::scope(|scope| walker.rec(scope, new_path, Some(metadata)))?.unwrap_or_default();
+        changes.splice(start..=end, new_changes);
+        adjust_dir_sizes(
+            changes,
+            walker.root_of(new_path),
+            new_path,
+            old_size,
+            new_size,
+        );
+        *total_size = *total_size - old_size + new_size;
+        changes.retain(|c| !matches!(c, IndexChange::RemoveDir(p) if p == old_path));
+    }
+    // files
+    let mut removed_files = HashMap::new();
+    if changes
+        .iter()
+        .any(|c| matches!(c, IndexChange::AddFile(..)))
+    {
+        for change in changes.iter() {
+            match change {
+                IndexChange::RemoveFile(path) => {
+                    if let Ok(Some(IndexEntry::File(file))) = index.get(&walker.index_path(path)) {
+                        add_removed_file(
+                            &mut removed_files,
+                            RemovedFile {
+                                path: path.clone(),
+                                file,
+                                existing_dir: parent(path).to_owned(),
+                                direct: true,
+                            },
+                        );
                     }
                 }
+                IndexChange::RemoveDir(path) => {
+                    collect_removed_files(walker, path, parent(path), &mut removed_files)
+                }
+                _ => {}
             }
         }
     }
-    // removals
-    for (removed_file, is_dir) in index_entries {
-        removals.push(if is_dir {
-            IndexChange::RemoveDir(rel_path.join(removed_file))
+    let mut file_moves = vec![];
+    let mut moved_changes = HashSet::new();
+    let mut moved_removals = HashSet::new();
+    for i in 0..changes.len() {
+        let IndexChange::AddFile(path, file) = &changes[i] else {
+            continue;
+        };
+        let Some(removed) = file.inode.and_then(|inode| removed_files.get(&inode)) else {
+            continue;
+        };
+        let old = &removed.file;
+        if file.special.is_some()
+            || old.size != file.size
+            || old.cmp_timestamp(file, settings) != Some(Ordering::Equal)
+            || matches!((&file.hash, &old.hash), (Some(new), Some(old)) if new != old)
+            // replacing an existing file isn't a move
+            || !matches!(index.get(&walker.index_path(path)), Ok(None))
+            || !source
+                .path(path)
+                .and_then(|path| fs::symlink_metadata(path).ok())
+                .is_some_and(|metadata| same_filesystem(source, &removed.existing_dir, &metadata))
+        {
+            continue;
+        }
+        let removed = removed_files
+            .remove(&file.inode.expect("found the removed file using the inode"))
+            .expect("just got this entry");
+        let (path, mut file) = (path.clone(), file.clone());
+        *total_size -= file.size;
+        adjust_dir_sizes(changes, walker.root_of(&path), &path, file.size, 0);
+        if file.metadata_changed(&removed.file, false, settings) {
+            if file.hash.is_none() {
+                file.hash = removed.file.hash;
+            }
+            file.blocks = removed.file.blocks;
+            changes[i] = IndexChange::UpdateMetadata(path.clone(), false, file);
         } else {
-            IndexChange::RemoveFile(rel_path.join(removed_file))
-        });
+            moved_changes.insert(i);
+        }
+        if removed.direct {
+            moved_removals.insert(removed.path.clone());
+        }
+        file_moves.push(IndexChange::Move(removed.path, path));
     }
-    // sorting
-    if let Some(sort_largest_first) = sort_by_size_largest {
-        if sort_largest_first {
-            ichanges.sort_by(|a, b| b.0.cmp(&a.0));
-        } else {
-            ichanges.sort_by_key(|v| v.0);
+    let mut i = 0;
+    changes.retain(|c| {
+        let keep = !moved_changes.contains(&i)
+            && !matches!(c, IndexChange::RemoveFile(p) if moved_removals.contains(p));
+        i += 1;
+        keep
+    });
+    // moves are applied first, so the old paths still exist
+    changes.splice(
+        0..0,
+        dir_moves
+            .into_iter()
+            .map(|(old_path, new_path, _)| IndexChange::Move(old_path, new_path))
+            .chain(file_moves),
+    );
+    Ok(())
+}
+
+fn add_removed_file(removed_files: &mut HashMap<u64, RemovedFile>, removed: RemovedFile) {
+    if removed.file.special.is_none()
+        && let Some(inode) = removed.file.inode
+    {
+        removed_files.insert(inode, removed);
+    }
+}
+/// Adds all files in a directory which will be removed. Errors are ignored.
+fn collect_removed_files(
+    walker: &Walker,
+    rel_path: &Path,
+    existing_dir: &Path,
+    removed_files: &mut HashMap<u64, RemovedFile>,
+) {
+    let index = walker.index;
+    let Ok(Some(entries)) = index.read_dir(&walker.index_path(rel_path)) else {
+        return;
+    };
+    for (name, is_dir) in entries {
+        let path = rel_path.join(name);
+        if is_dir {
+            collect_removed_files(walker, &path, existing_dir, removed_files);
+        } else if let Ok(Some(IndexEntry::File(file))) = index.get(&walker.index_path(&path)) {
+            add_removed_file(
+                removed_files,
+                RemovedFile {
+                    path,
+                    file,
+                    existing_dir: existing_dir.to_owned(),
+                    direct: false,
+                },
+            );
+        }
+    }
+}
+
+/// The directories which have to be compared to find all changes in `dirs`, sorted and without duplicates.
+/// Directories which don't exist in both source and index are replaced by their parent directory,
+/// which finds their removal or compares them as new directories.
+fn existing_dirs(source: &Source, index: &Index, dirs: &[PathBuf]) -> BTreeSet<PathBuf> {
+    let exists = |dir: &Path| {
+        source.path(dir).is_some_and(|path| path.is_dir())
+            && matches!(index.read_dir(dir), Ok(Some(_)))
+    };
+    let mut existing = BTreeSet::new();
+    for dir in dirs {
+        let mut dir = dir.as_path();
+        while !dir.as_os_str().is_empty() && !exists(dir) {
+            dir = parent(dir);
         }
+        existing.insert(dir.to_owned());
     }
-    // combine everything
-    if !dir_is_new && removals.is_empty() && ichanges.is_empty() {
-        return Ok(None);
+    existing
+}
+
+fn parent(rel_path: &Path) -> &Path {
+    rel_path.parent().unwrap_or(Path::new(""))
+}
+/// True if `metadata` is on the same filesystem as `existing_dir` in source.
+/// Inode numbers are only unique within one filesystem.
+fn same_filesystem(source: &Source, existing_dir: &Path, metadata: &fs::Metadata) -> bool {
+    source
+        .path(existing_dir)
+        .and_then(|dir| fs::metadata(dir).ok())
+        .is_some_and(|dir| dir.dev() == metadata.dev())
+}
+/// Updates the sizes of the directories containing `rel_path` after the size of `rel_path` changed
+/// Only directories in `root` are updated, because the sizes of other roots' directories don't include it.
+fn adjust_dir_sizes(
+    changes: &mut [IndexChange],
+    root: &Path,
+    rel_path: &Path,
+    old_size: u64,
+    new_size: u64,
+) {
+    for change in changes {
+        if let IndexChange::AddDir(dir, _, size) = change
+            && dir.starts_with(root)
+            && dir.as_path() != rel_path
+            && rel_path.starts_with(dir)
+        {
+            *size = *size - old_size + new_size;
+        }
     }
-    let changes = [IndexChange::AddDir(
-        rel_path.to_path_buf(),
-        dir_is_new,
-        total_size,
-    )]
-    .into_iter()
-    .chain(removals.into_iter())
-    .chain(ichanges.into_iter().flat_map(|(_, v)| v))
-    .collect();
-    Ok(Some((total_size, changes)))
 }
@@ -0,0 +1,188 @@
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    index::{self, Index, IndexEntry},
+    indexfile::IndexFile,
+    rebuild_index::add_target_entry,
+    update_index::{DiffError, Settings},
+};
+
+#[derive(Debug)]
+pub enum Problem {
+    /// In the index, but not on the target. The bool is true for directories.
+    Missing(bool),
+    /// The index contains a directory, but the target contains a file, or the other way around.
+    /// The bool is true if the index contains a directory.
+    WrongType(bool),
+    /// The file has a different size on the target (index size, target size)
+    WrongSize(u64, u64),
+    /// The file has a different modification time on the target
+    WrongTimestamp,
+    /// The symlink points somewhere else on the target (index link, target link)
+    WrongSymlink(PathBuf, PathBuf),
+    /// On the target, but not in the index
+    NotInIndex,
+}
+
+/// Walks `index` and `target` together and returns all differences between them.
+/// `settings` determines how timestamps are compared.
+pub fn verify(
+    index: &Index,
+    target: &Path,
+    settings: &Settings,
+) -> Result<Vec<(PathBuf, Problem)>, DiffError> {
+    let mut problems = vec![];
+    rec(index, target, Path::new(""), settings, &mut problems)?;
+    Ok(problems)
+}
+
+fn rec(
+    index: &Index,
+    target: &Path,
+    rel_path: &Path,
+    settings: &Settings,
+    problems: &mut Vec<(PathBuf, Problem)>,
+) -> Result<(), DiffError> {
+    let mut index_entries = index
+        .read_dir(rel_path)
+        .map_err(|e| {
+            (
+                "reading directory in index".to_owned(),
+                index.path().join(rel_path),
+                e,
+            )
+        })?
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<Vec<_>>();
+    index_entries.sort_unstable();
+    let target_dir = target.join(rel_path);
+    let mut target_entries = fs::read_dir(&target_dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<Result<HashSet<OsString>, _>>()
+        })
+        .map_err(|e| ("getting entries".to_owned(), target_dir.clone(), e))?;
+    for (name, is_dir) in index_entries {
+        let rel_path = rel_path.join(&name);
+        if !target_entries.remove(&name) {
+            problems.push((rel_path, Problem::Missing(is_dir)));
+            continue;
+        }
+        let target_path = target.join(&rel_path);
+        let metadata = fs::symlink_metadata(&target_path)
+            .map_err(|e| ("getting metadata".to_owned(), target_path.clone(), e))?;
+        if is_dir {
+            if metadata.is_dir() {
+                rec(index, target, &rel_path, settings, problems)?;
+            } else {
+                problems.push((rel_path, Problem::WrongType(true)));
+            }
+            continue;
+        }
+        let entry = index.get(&rel_path).map_err(|e| {
+            (
+                "reading index file".to_owned(),
+                index.path().join(&rel_path),
+                e,
+            )
+        })?;
+        match entry {
+            Some(IndexEntry::Symlink(index_link)) => {
+                if !metadata.is_symlink() {
+                    problems.push((rel_path, Problem::WrongType(false)));
+                } else {
+                    let target_link = fs::read_link(&target_path).map_err(|e| {
+                        (
+                            "couldn't read symlink contents".to_owned(),
+                            target_path.clone(),
+                            e,
+                        )
+                    })?;
+                    if target_link != index_link {
+                        problems.push((rel_path, Problem::WrongSymlink(index_link, target_link)));
+                    }
+                }
+            }
+            Some(IndexEntry::File(index_file)) => {
+                let target_file = IndexFile::new_from_metadata(&metadata);
+                if !(metadata.is_file() || index_file.special.is_some())
+                    || target_file.special != index_file.special
+                {
+                    problems.push((rel_path, Problem::WrongType(false)));
+                } else if target_file.size != index_file.size {
+                    problems.push((
+                        rel_path,
+                        Problem::WrongSize(index_file.size, target_file.size),
+                    ));
+                } else if !settings.ignore_timestamp
+                    && target_file
+                        .cmp_timestamp(&index_file, settings)
+                        .is_some_and(|ord| ord != Ordering::Equal)
+                {
+                    problems.push((rel_path, Problem::WrongTimestamp));
+                }
+            }
+            // it was just listed in its parent directory
+            Some(IndexEntry::Dir) | None => {}
+        }
+    }
+    let mut target_entries = target_entries.into_iter().collect::<Vec<_>>();
+    target_entries.sort_unstable();
+    for name in target_entries {
+        let rel_path = rel_path.join(name);
+        if !index::is_reserved(&rel_path) {
+            problems.push((rel_path, Problem::NotInIndex));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the entries with problems from the index, so they will be copied again during the next backup.
+/// Entries with the wrong type are replaced by the entry which is on the target,
+/// so the next backup removes it from the target before copying the entry from the source.
+/// Entries which are only on the target can't be fixed this way and are skipped.
+/// Returns the number of entries which were removed or replaced.
+pub fn repair(index: &mut Index, target: &Path, problems: &[(PathBuf, Problem)]) -> usize {
+    let mut removed = 0;
+    for (path, problem) in problems {
+        let result = match problem {
+            Problem::NotInIndex => continue,
+            Problem::Missing(true) | Problem::WrongType(true) => index.remove_dir(path),
+            Problem::Missing(false)
+            | Problem::WrongType(false)
+            | Problem::WrongSize(..)
+            | Problem::WrongTimestamp
+            | Problem::WrongSymlink(..) => index.remove_file(path),
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "[warn] couldn't remove {:?} from the index: {e}",
+                index.path().join(path)
+            );
+            continue;
+        }
+        removed += 1;
+        if let Problem::WrongType(_) = problem {
+            let t = target.join(path);
+            if let Err((what, path, e)) = fs::symlink_metadata(&t)
+                .map_err(|e| ("getting metadata".to_owned(), t.clone(), e))
+                .and_then(|metadata| add_target_entry(index, path, &t, &metadata))
+            {
+                eprintln!("[warn] {what} {path:?}: {e}");
+            }
+        }
+    }
+    if let Err(e) = index.save() {
+        eprintln!("[err] couldn't save index {:?}: {e}", index.path());
+        return 0;
+    }
+    removed
+}
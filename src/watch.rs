@@ -0,0 +1,222 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
+    fs, io,
+    mem::size_of,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    config::{FsEntry, Ignore},
+    index,
+    source::Source,
+    update_index::Settings,
+};
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_ONLYDIR
+    | libc::IN_DONT_FOLLOW
+    | libc::IN_EXCL_UNLINK;
+
+/// Changes which were found by inotify, but haven't been backed up yet
+#[derive(Default)]
+pub struct Batch {
+    /// directories (relative to source) which contain changed entries
+    pub dirty: HashSet<PathBuf>,
+    /// some events were lost, so the entire source has to be compared again
+    pub rescan: bool,
+}
+
+/// Watches every directory in source which isn't ignored using inotify
+pub struct Watcher<'a> {
+    source: &'a Source,
+    ignore: &'a Ignore,
+    settings: &'a Settings,
+    fd: i32,
+    /// watch descriptor -> watched directory (relative to source)
+    watches: HashMap<i32, PathBuf>,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(source: &'a Source, ignore: &'a Ignore, settings: &'a Settings) -> io::Result<Self> {
+        for (_, path) in source.roots() {
+            fs::metadata(path)?;
+        }
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut watcher = Self {
+            source,
+            ignore,
+            settings,
+            fd,
+            watches: HashMap::new(),
+        };
+        watcher.add_watches(Path::new(""));
+        Ok(watcher)
+    }
+
+    /// How many directories are being watched
+    pub fn len(&self) -> usize {
+        self.watches.len()
+    }
+
+    /// Watches the directory at `rel_path` and all directories in it.
+    /// Errors are logged, and the directories which couldn't be watched are skipped.
+    /// Directories which are already watched are updated, for example if they were moved.
+    pub fn add_watches(&mut self, rel_path: &Path) {
+        let Some(path) = self.source.path(rel_path) else {
+            // the root of named sources can't be watched, but the sources can
+            for (name, _) in self.source.roots() {
+                self.add_watches(name);
+            }
+            return;
+        };
+        let c_path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(e) => {
+                eprintln!("[warn] couldn't watch {path:?}: {e}");
+                return;
+            }
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOSPC) {
+                eprintln!(
+                    "[warn] couldn't watch {path:?}: {e}\n       Changes in it will only be found after a full rescan. Try increasing fs.inotify.max_user_watches."
+                );
+            } else if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("[warn] couldn't watch {path:?}: {e}");
+            }
+            return;
+        }
+        self.watches.insert(wd, rel_path.to_owned());
+        let dev = fs::metadata(&path).map(|metadata| metadata.dev());
+        let Ok(entries) = fs::read_dir(&path) else {
+            // will be found by the diff, if it still exists
+            return;
+        };
+        for entry in entries.flatten() {
+            if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+            let rel_path = rel_path.join(entry.file_name());
+            if index::is_reserved(&rel_path) || self.is_ignored(&rel_path, true) {
+                continue;
+            }
+            // like in the diff, directories on other filesystems than their parent directory are skipped
+            if self.settings.one_file_system
+                && entry
+                    .metadata()
+                    .is_ok_and(|metadata| dev.as_ref().is_ok_and(|dev| metadata.dev() != *dev))
+            {
+                continue;
+            }
+            self.add_watches(&rel_path);
+        }
+    }
+
+    /// Waits for events and adds the changed directories to `batch`.
+    /// Returns false if there were no events before the `timeout`.
+    pub fn wait(&mut self, timeout: Option<Duration>, batch: &mut Batch) -> io::Result<bool> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+            0 => return Ok(false),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                return if e.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(e)
+                };
+            }
+            _ => {}
+        }
+        let mut buf = vec![0u8; 64 * 1024];
+        let len = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted {
+                Ok(true)
+            } else {
+                Err(e)
+            };
+        }
+        let len = len as usize;
+        let mut offset = 0;
+        while offset + size_of::<libc::inotify_event>() <= len {
+            // the buffer isn't aligned for `inotify_event`
+            let event = unsafe {
+                std::ptr::read_unaligned(buf[offset..].as_ptr().cast::<libc::inotify_event>())
+            };
+            let name_start = offset + size_of::<libc::inotify_event>();
+            offset = name_start + event.len as usize;
+            // the name is padded with null bytes
+            let name = &buf[name_start..offset.min(len)];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            self.handle_event(event.wd, event.mask, OsStr::from_bytes(name), batch);
+        }
+        Ok(true)
+    }
+
+    fn handle_event(&mut self, wd: i32, mask: u32, name: &OsStr, batch: &mut Batch) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            batch.rescan = true;
+            return;
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            // the directory was removed
+            self.watches.remove(&wd);
+            return;
+        }
+        let Some(dir) = self.watches.get(&wd).cloned() else {
+            return;
+        };
+        if name.is_empty() {
+            // the directory's own metadata changed
+            batch.dirty.insert(dir);
+            return;
+        }
+        let rel_path = dir.join(name);
+        let is_dir = mask & libc::IN_ISDIR != 0;
+        // changes to the index or target must not start another backup
+        if index::is_reserved(&rel_path) || self.is_ignored(&rel_path, is_dir) {
+            return;
+        }
+        batch.dirty.insert(dir);
+        if is_dir {
+            if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                self.add_watches(&rel_path);
+            }
+            // a directory's metadata is compared with its contents
+            batch.dirty.insert(rel_path);
+        }
+    }
+
+    fn is_ignored(&self, rel_path: &Path, is_dir: bool) -> bool {
+        self.ignore.matches_or_default(&FsEntry {
+            path: rel_path,
+            is_directory: Some(is_dir),
+        })
+    }
+}
+
+impl Drop for Watcher<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}